@@ -0,0 +1,276 @@
+//! Module for checkpoints of CSV processing: the input position together with an engine snapshot.
+
+use crate::error::ValidationError;
+use crate::snapshot::save_json;
+use crate::snapshot::Snapshot;
+use crate::Result;
+use csv::Position;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufReader;
+use std::io::Read;
+use std::path::Path;
+
+///Number of records processed between two checkpoints when no interval is set.
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 100_000;
+
+///Fields of a saved checkpoint besides the snapshot.
+#[derive(Deserialize)]
+struct SavedFields {
+    byte: u64,
+    line: u64,
+    record: u64,
+    input_hash: u64,
+    #[serde(default)]
+    outputs: OutputLengths,
+}
+
+///Lengths of the outputs when a checkpoint was saved, a resumed run cuts them back to these lengths
+///so rows written after the checkpoint are not written twice. Outputs that were not written are `None`.
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct OutputLengths {
+    ///Length of the dead letter file.
+    pub dead_letter: Option<u64>,
+    ///Length of the audit trail file.
+    pub audit: Option<u64>,
+    ///Length of the charged fees file.
+    pub fee_log: Option<u64>,
+    ///Length of the event log file.
+    pub event_log: Option<u64>,
+}
+
+///FNV-1a hash of the input before a checkpoint or after a logged record, it is stable across builds and platforms.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct InputHash(u64);
+
+impl InputHash {
+    ///Continue hashing after bytes that hashed to the given value.
+    pub fn from_value(value: u64) -> Self {
+        Self(value)
+    }
+
+    ///Hash the given bytes after the ones hashed so far.
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    ///Hash of every byte so far.
+    pub fn value(self) -> u64 {
+        self.0
+    }
+
+    ///Hash the first `length` bytes of an input, a shorter input is hashed as a whole.
+    pub fn of_prefix<R: Read>(input: R, length: u64) -> Result<Self> {
+        let mut hash = Self::default();
+        let mut input = input.take(length);
+        let mut buffer = [0; 8 * 1024];
+        loop {
+            match input.read(&mut buffer)? {
+                0 => return Ok(hash),
+                count => hash.update(&buffer[..count]),
+            }
+        }
+    }
+}
+
+///Hash of no bytes is the FNV-1a offset basis.
+impl Default for InputHash {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+///Input position of the next record to process and the engine state at that point.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Checkpoint {
+    ///Byte offset of the next record.
+    pub byte: u64,
+    ///Line of the next record.
+    pub line: u64,
+    ///Index of the next record, the header is record zero.
+    pub record: u64,
+    ///Hash of the input before the position, a checkpoint is only resumed on the input it was saved for.
+    pub input_hash: u64,
+    ///Lengths of the outputs after every record before the position was processed.
+    pub outputs: OutputLengths,
+    ///Engine state after every record before the position was processed.
+    pub snapshot: Snapshot,
+}
+
+impl Checkpoint {
+    ///Create a checkpoint at a CSV reader position, the input before it has the given hash.
+    pub fn new(position: &Position, input_hash: InputHash, snapshot: Snapshot) -> Self {
+        Self {
+            byte: position.byte(),
+            line: position.line(),
+            record: position.record(),
+            input_hash: input_hash.value(),
+            outputs: OutputLengths::default(),
+            snapshot,
+        }
+    }
+
+    ///Set the lengths of the outputs.
+    pub fn with_outputs(mut self, outputs: OutputLengths) -> Self {
+        self.outputs = outputs;
+        self
+    }
+
+    ///CSV reader position of the next record.
+    pub fn position(&self) -> Position {
+        let mut position = Position::new();
+        position
+            .set_byte(self.byte)
+            .set_line(self.line)
+            .set_record(self.record);
+        position
+    }
+
+    ///Read a checkpoint written as JSON, the snapshot version is checked before anything else.
+    pub fn read<R: Read>(reader: R) -> Result<Self> {
+        let mut value: Value = serde_json::from_reader(reader)?;
+        let snapshot = value
+            .get_mut("snapshot")
+            .map(Value::take)
+            .unwrap_or_default();
+        let snapshot = Snapshot::from_value(snapshot)?;
+        let fields: SavedFields = serde_json::from_value(value)?;
+
+        Ok(Self {
+            byte: fields.byte,
+            line: fields.line,
+            record: fields.record,
+            input_hash: fields.input_hash,
+            outputs: fields.outputs,
+            snapshot,
+        })
+    }
+
+    ///Read the checkpoint at a path, `None` when no checkpoint was saved there.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(Self::read(BufReader::new(File::open(path)?))?))
+    }
+
+    ///Save the checkpoint to a file. It is written next to the file first and then renamed over it,
+    ///so a crash while saving leaves the previous checkpoint intact.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        save_json(self, path)
+    }
+}
+
+///Open an output for writing after cutting it back to the given length, it is created when missing.
+///An output without a length is emptied, the same as a run that is not resumed.
+pub fn open_output<P: AsRef<Path>>(path: P, length: Option<u64>) -> Result<File> {
+    let path = path.as_ref();
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    cut_to(&file, path, length.unwrap_or(0))?;
+    Ok(file)
+}
+
+///Cut a file back to the given length, a shorter file was changed since the checkpoint was saved.
+pub(crate) fn cut_to(file: &File, path: &Path, length: u64) -> Result<()> {
+    if file.metadata()?.len() < length {
+        return Err(ValidationError::TruncatedOutput(path.display().to_string()).into());
+    }
+    file.set_len(length)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::structs::Amount;
+    use crate::structs::ClientId;
+    use crate::structs::Transaction;
+    use crate::structs::TxId;
+    use rust_decimal::Decimal;
+    use std::convert::TryFrom;
+    use std::fs;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    ///Path of a temporary file unique to one test.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("csv_parser_{}_{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn saved_checkpoint_reads_back_the_same() {
+        let path = temp_path("checkpoint_round_trip.json");
+        let mut position = Position::new();
+        position.set_byte(42).set_line(3).set_record(2);
+        //the rejected withdrawal is part of the snapshot
+        let mut engine = Engine::new();
+        engine.process(Transaction::Withdrawal {
+            client: ClientId(1),
+            tx: TxId("7".parse().unwrap()),
+            amount: Amount::try_from(Decimal::new(5, 0)).unwrap(),
+        });
+        let outputs = OutputLengths {
+            dead_letter: Some(10),
+            event_log: Some(20),
+            ..OutputLengths::default()
+        };
+        let checkpoint = Checkpoint::new(&position, InputHash::from_value(9), engine.snapshot())
+            .with_outputs(outputs);
+        checkpoint.save(&path).unwrap();
+
+        let saved = Checkpoint::load(&path).unwrap().unwrap();
+        assert_eq!(saved, checkpoint);
+        assert_eq!(saved.position(), position);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_checkpoint_loads_as_none() {
+        assert_eq!(Checkpoint::load(temp_path("missing.json")).unwrap(), None);
+    }
+
+    #[test]
+    fn prefix_hash_equals_hashing_in_parts() {
+        let input = b"type,client,tx,amount\ndeposit,1,1,10\n";
+        let mut hash = InputHash::default();
+        hash.update(&input[..10]);
+        hash.update(&input[10..30]);
+
+        assert_eq!(InputHash::of_prefix(&input[..], 30).unwrap(), hash);
+        assert_ne!(InputHash::of_prefix(&input[..], 29).unwrap(), hash);
+    }
+
+    #[test]
+    fn output_is_cut_back_to_the_saved_length() {
+        let path = temp_path("output_cut.csv");
+        fs::write(&path, "header\nfirst\nafter checkpoint\n").unwrap();
+
+        let mut file = open_output(&path, Some(13)).unwrap();
+        writeln!(file, "second").unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "header\nfirst\nsecond\n"
+        );
+
+        //an output shorter than the saved length was changed after the checkpoint
+        assert!(matches!(
+            open_output(&path, Some(100)),
+            Err(crate::Error::Validation(ValidationError::TruncatedOutput(
+                _
+            )))
+        ));
+        open_output(&path, None).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        fs::remove_file(&path).unwrap();
+    }
+}
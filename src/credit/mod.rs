@@ -0,0 +1,163 @@
+//! Module for per client credit limits, the amount a client can overdraw it's available funds by.
+
+use crate::error::ValidationError;
+use crate::structs::AccountData;
+use crate::structs::AccountKey;
+use crate::structs::ClientId;
+use crate::structs::ClientIdValue;
+use crate::structs::Currency;
+use crate::validation::DEFAULT_MAX_SCALE;
+use crate::Result;
+use csv::ReaderBuilder;
+use csv::Trim;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::io::Read;
+
+///Row of a credit limit overrides file.
+#[derive(Debug, Deserialize)]
+struct CreditLimitRecord {
+    client: ClientIdValue,
+    limit: Decimal,
+}
+
+///Credit limits of every client. Clients without an override get the default, which is zero unless set.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct CreditLimits {
+    ///Limit of clients without an override.
+    default: Decimal,
+    ///Limits of single clients.
+    overrides: HashMap<ClientId, Decimal>,
+}
+
+impl CreditLimits {
+    ///Create credit limits where no client can overdraw.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///Set the limit of clients without an override.
+    pub fn with_default(mut self, limit: Decimal) -> Result<Self> {
+        check_limit(limit)?;
+        self.default = limit;
+        Ok(self)
+    }
+
+    ///Set the limit of a single client.
+    pub fn with_override(mut self, client: ClientId, limit: Decimal) -> Result<Self> {
+        check_limit(limit)?;
+        self.overrides.insert(client, limit);
+        Ok(self)
+    }
+
+    ///Load overrides from a `client,limit` CSV, a later row for the same client wins.
+    pub fn with_overrides<R: Read>(mut self, reader: R) -> Result<Self> {
+        let mut csv_reader = ReaderBuilder::new()
+            .has_headers(true)
+            .trim(Trim::All)
+            .from_reader(reader);
+
+        for record in csv_reader.deserialize::<CreditLimitRecord>() {
+            let record = record?;
+            self = self.with_override(ClientId(record.client), record.limit)?;
+        }
+
+        Ok(self)
+    }
+
+    ///Credit limit of a client.
+    pub fn limit(&self, client: ClientId) -> Decimal {
+        self.overrides.get(&client).copied().unwrap_or(self.default)
+    }
+}
+
+///Credit a client has not used yet. The limit is shared by every account of the client, not given to each currency,
+///so overdrafts in all of them use it up. Currencies are not converted, an overdraft of 1 uses 1 of the limit.
+pub fn remaining_credit(
+    accounts: &BTreeMap<AccountKey, AccountData>,
+    client: ClientId,
+    limit: Decimal,
+) -> Decimal {
+    accounts
+        .range(AccountKey::new(client, Currency::default())..)
+        .take_while(|(key, _)| key.client == client)
+        .filter(|(_, data)| data.available < Decimal::ZERO)
+        .try_fold(limit, |remaining, (_, data)| {
+            remaining.checked_add(data.available)
+        })
+        .map_or(Decimal::ZERO, |remaining| remaining.max(Decimal::ZERO))
+}
+
+///Check that a limit is not negative and has at most 4 places past the decimal point.
+fn check_limit(limit: Decimal) -> std::result::Result<(), ValidationError> {
+    if limit < Decimal::ZERO || limit.normalize().scale() > DEFAULT_MAX_SCALE {
+        return Err(ValidationError::InvalidCreditLimit(limit.to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn overrides_replace_the_default_and_later_rows_win() {
+        let limits = CreditLimits::new()
+            .with_default(dec("10"))
+            .unwrap()
+            .with_overrides("client, limit\n2, 5\n3, 1\n2, 7.5\n".as_bytes())
+            .unwrap();
+
+        assert_eq!(limits.limit(ClientId(1)), dec("10"));
+        assert_eq!(limits.limit(ClientId(2)), dec("7.5"));
+        assert_eq!(limits.limit(ClientId(3)), dec("1"));
+    }
+
+    #[test]
+    fn negative_or_too_precise_limits_are_refused() {
+        assert!(CreditLimits::new().with_default(dec("-1")).is_err());
+        assert!(CreditLimits::new()
+            .with_override(ClientId(1), dec("0.00001"))
+            .is_err());
+        assert!(CreditLimits::new()
+            .with_overrides("client,limit\n1,-5\n".as_bytes())
+            .is_err());
+    }
+
+    #[test]
+    fn overdrafts_of_every_account_use_the_shared_limit() {
+        let mut accounts = BTreeMap::new();
+        for (id, currency, available) in [
+            (1, "EUR", "-2"),
+            (1, "USD", "-1.5"),
+            (1, "GBP", "4"),
+            (2, "EUR", "-1"),
+        ]
+        .iter()
+        {
+            let data = AccountData {
+                available: dec(available),
+                total: dec(available),
+                ..AccountData::default()
+            };
+            accounts.insert(
+                AccountKey::new(ClientId(*id), Currency::new(currency).unwrap()),
+                data,
+            );
+        }
+
+        assert_eq!(
+            remaining_credit(&accounts, ClientId(1), dec("5")),
+            dec("1.5")
+        );
+        assert_eq!(remaining_credit(&accounts, ClientId(1), dec("3")), dec("0"));
+        assert_eq!(remaining_credit(&accounts, ClientId(2), dec("5")), dec("4"));
+        assert_eq!(remaining_credit(&accounts, ClientId(3), dec("5")), dec("5"));
+    }
+}
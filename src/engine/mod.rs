@@ -0,0 +1,1714 @@
+//! Module for the transaction engine. Holds account and transaction state independent of any input source.
+
+use crate::checkpoint::InputHash;
+use crate::checkpoint::OutputLengths;
+use crate::credit::remaining_credit;
+use crate::credit::CreditLimits;
+use crate::error::ValidationError;
+use crate::fees::FeeEntry;
+use crate::fees::FeeSchedule;
+use crate::snapshot::Snapshot;
+use crate::snapshot::SnapshotAccount;
+use crate::snapshot::SnapshotTransaction;
+use crate::snapshot::SNAPSHOT_VERSION;
+use crate::structs::AccountData;
+use crate::structs::AccountKey;
+use crate::structs::ClientId;
+use crate::structs::Currency;
+use crate::structs::DisputeState;
+use crate::structs::RecordError;
+use crate::structs::StoredTransaction;
+use crate::structs::Transaction;
+use crate::structs::TransactionRecord;
+use crate::structs::TransactionType;
+use crate::structs::TxId;
+use crate::validation::AmountError;
+use crate::validation::Validator;
+use crate::wal::AccountDelta;
+use crate::wal::Event;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::hash_map::Entry;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::BufRead;
+use std::str::FromStr;
+
+///Outcome of processing a single transaction.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Outcome {
+    ///Transaction was applied to the account state.
+    Applied,
+    ///Transaction was rejected and did not change any account.
+    Rejected(RejectReason),
+}
+
+///Reason for rejecting a transaction.
+#[derive(Debug, PartialEq, Clone)]
+pub enum RejectReason {
+    ///Record could not be deserialized into a transaction.
+    Malformed(String),
+    ///Transaction id was not provided.
+    MissingTransactionId,
+    ///Amount was not provided for a deposit or withdrawal.
+    MissingAmount,
+    ///Amount of a deposit or withdrawal failed validation.
+    InvalidAmount(AmountError),
+    ///Reason was not provided for an adjustment.
+    MissingReason,
+    ///Receiving client was not provided for a transfer.
+    MissingRecipient,
+    ///Currency was not provided for an adjustment of a client that only has accounts in other currencies.
+    MissingCurrency,
+    ///Receiving client of a transfer is the sending client.
+    SelfTransfer,
+    ///Currency code is not 3 letters.
+    InvalidCurrency(String),
+    ///Transaction id was already used by a previous deposit, withdrawal or transfer.
+    DuplicateTransaction(TxId),
+    ///Client does not have enough available funds for a withdrawal or transfer and it's fee.
+    InsufficientFunds,
+    ///Withdrawal or transfer would overdraw the client by more than it's credit limit.
+    CreditLimitExceeded,
+    ///Transaction would take a balance past the largest value that can be stored.
+    Overflow,
+    ///Client account is locked.
+    AccountLocked(ClientId),
+    ///Unlock of a client that has no accounts.
+    UnknownClient(ClientId),
+    ///Referenced transaction was not found.
+    UnknownTransaction(TxId),
+    ///Referenced transaction was rejected so it never moved any funds.
+    NotApplied(TxId),
+    ///Referenced transaction belongs to another client.
+    ClientMismatch(TxId),
+    ///Referenced transaction is already under dispute.
+    AlreadyDisputed(TxId),
+    ///Referenced transaction is not under dispute.
+    NotDisputed(TxId),
+    ///Dispute of the referenced transaction was already resolved or charged back.
+    DisputeClosed(TxId),
+    ///Referenced transaction is a withdrawal and the dispute policy rejects those.
+    WithdrawalDispute(TxId),
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::Malformed(e) => write!(f, "malformed record: {}", e),
+            RejectReason::MissingTransactionId => write!(f, "missing transaction id"),
+            RejectReason::MissingAmount => write!(f, "missing amount"),
+            RejectReason::InvalidAmount(e) => write!(f, "invalid amount: {}", e),
+            RejectReason::MissingReason => write!(f, "missing reason"),
+            RejectReason::MissingRecipient => write!(f, "missing receiving client"),
+            RejectReason::MissingCurrency => write!(f, "missing currency"),
+            RejectReason::SelfTransfer => write!(f, "transfer to the sending client"),
+            RejectReason::InvalidCurrency(code) => write!(f, "invalid currency '{}'", code),
+            RejectReason::DuplicateTransaction(id) => {
+                write!(f, "duplicate transaction id '{}'", id)
+            }
+            RejectReason::InsufficientFunds => write!(f, "insufficient available funds"),
+            RejectReason::CreditLimitExceeded => write!(f, "credit limit exceeded"),
+            RejectReason::Overflow => write!(f, "balance would overflow"),
+            RejectReason::AccountLocked(client) => write!(f, "client '{}' is locked", client),
+            RejectReason::UnknownClient(client) => write!(f, "unknown client '{}'", client),
+            RejectReason::UnknownTransaction(id) => write!(f, "unknown transaction id '{}'", id),
+            RejectReason::NotApplied(id) => {
+                write!(
+                    f,
+                    "transaction '{}' was rejected and cannot be referenced",
+                    id
+                )
+            }
+            RejectReason::ClientMismatch(id) => {
+                write!(f, "transaction '{}' belongs to another client", id)
+            }
+            RejectReason::AlreadyDisputed(id) => {
+                write!(f, "transaction '{}' is already disputed", id)
+            }
+            RejectReason::NotDisputed(id) => write!(f, "transaction '{}' is not disputed", id),
+            RejectReason::DisputeClosed(id) => {
+                write!(f, "dispute of transaction '{}' is already closed", id)
+            }
+            RejectReason::WithdrawalDispute(id) => {
+                write!(
+                    f,
+                    "transaction '{}' is a withdrawal and cannot be disputed",
+                    id
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for RejectReason {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RejectReason::InvalidAmount(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<RecordError> for RejectReason {
+    fn from(e: RecordError) -> Self {
+        match e {
+            RecordError::MissingTransactionId => RejectReason::MissingTransactionId,
+            RecordError::MissingAmount => RejectReason::MissingAmount,
+            RecordError::InvalidAmount(e) => RejectReason::InvalidAmount(e),
+            RecordError::MissingReason => RejectReason::MissingReason,
+            RecordError::MissingRecipient => RejectReason::MissingRecipient,
+            RecordError::SelfTransfer => RejectReason::SelfTransfer,
+            RecordError::InvalidCurrency(code) => RejectReason::InvalidCurrency(code),
+        }
+    }
+}
+
+impl From<AmountError> for RejectReason {
+    fn from(e: AmountError) -> Self {
+        RejectReason::InvalidAmount(e)
+    }
+}
+
+///Admin action recorded by the engine.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct AuditEntry {
+    ///Client the action was applied to.
+    pub client: ClientId,
+    ///Either unlock, freeze or adjust.
+    #[serde(rename = "type")]
+    pub col_type: TransactionType,
+    ///Signed amount of an adjustment.
+    pub amount: Option<Decimal>,
+    ///Reason of an adjustment.
+    pub reason: Option<String>,
+    ///Currency of the account the action was applied to.
+    pub currency: Currency,
+}
+
+impl From<&Transaction> for AuditEntry {
+    fn from(data: &Transaction) -> Self {
+        let (amount, reason) = match data {
+            Transaction::Adjust { amount, reason, .. } => (Some(*amount), Some(reason.to_owned())),
+            _ => (None, None),
+        };
+
+        Self {
+            client: data.client(),
+            col_type: data.col_type(),
+            amount,
+            reason,
+            currency: Currency::default(),
+        }
+    }
+}
+
+///How disputes over withdrawals are handled. Disputes over deposits are not affected.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DisputePolicy {
+    ///Treat a disputed withdrawal like a deposit: funds move from available to held and a chargeback takes them
+    ///from total. This debits the client a second time and is the default to keep existing results unchanged.
+    Legacy,
+    ///Reject disputes over withdrawals.
+    Reject,
+    ///Hold a credit for the withdrawn funds: a dispute adds them to held and total, a resolve takes them back
+    ///and a chargeback returns them to available.
+    Reversed,
+}
+
+///Withdrawals are disputed like deposits unless another policy is requested.
+impl Default for DisputePolicy {
+    fn default() -> Self {
+        DisputePolicy::Legacy
+    }
+}
+
+impl FromStr for DisputePolicy {
+    type Err = ValidationError;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "legacy" => Ok(DisputePolicy::Legacy),
+            "reject" => Ok(DisputePolicy::Reject),
+            "reversed" => Ok(DisputePolicy::Reversed),
+            _ => Err(ValidationError::InvalidOptionValue(
+                "withdrawal-disputes".to_owned(),
+                value.to_owned(),
+            )),
+        }
+    }
+}
+
+///What a locked account still accepts. Admin actions are always accepted.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LockPolicy {
+    ///Reject every transaction. This is the default and matches the original behavior.
+    BlockAll,
+    ///Reject withdrawals and outgoing transfers only, deposits, incoming transfers and disputes are still applied.
+    BlockWithdrawals,
+    ///Reject deposits, withdrawals, transfers and new disputes, disputes that are already open can still be resolved or
+    ///charged back.
+    ResolveDisputes,
+}
+
+///Locked accounts reject everything unless another policy is requested.
+impl Default for LockPolicy {
+    fn default() -> Self {
+        LockPolicy::BlockAll
+    }
+}
+
+impl LockPolicy {
+    ///Check if a locked account accepts a transaction of this type.
+    ///A transfer is checked as a transfer on the sending side and as a deposit on the receiving side.
+    pub fn allows(&self, col_type: TransactionType) -> bool {
+        match self {
+            LockPolicy::BlockAll => false,
+            LockPolicy::BlockWithdrawals => {
+                col_type != TransactionType::Withdrawal && col_type != TransactionType::Transfer
+            }
+            LockPolicy::ResolveDisputes => {
+                col_type == TransactionType::Resolve || col_type == TransactionType::Chargeback
+            }
+        }
+    }
+}
+
+impl FromStr for LockPolicy {
+    type Err = ValidationError;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "block-all" => Ok(LockPolicy::BlockAll),
+            "block-withdrawals" => Ok(LockPolicy::BlockWithdrawals),
+            "resolve-disputes" => Ok(LockPolicy::ResolveDisputes),
+            _ => Err(ValidationError::InvalidOptionValue(
+                "lock-policy".to_owned(),
+                value.to_owned(),
+            )),
+        }
+    }
+}
+
+///Transaction engine. Applies deposit, withdrawal, dispute, resolve, chargeback and admin rules.
+#[derive(Debug, Default)]
+pub struct Engine {
+    ///Client data indexed by client id and currency, ordered so exports are deterministic.
+    accounts_map: BTreeMap<AccountKey, AccountData>,
+    ///Deposits, withdrawals and transfers indexed by transaction id, used for disputes.
+    transactions_map: HashMap<TxId, StoredTransaction>,
+    ///Deposits, withdrawals and transfers that were rejected, indexed by transaction id. These are never disputable.
+    rejected_map: HashMap<TxId, Transaction>,
+    ///How disputes over withdrawals are handled.
+    dispute_policy: DisputePolicy,
+    ///What a locked account still accepts.
+    lock_policy: LockPolicy,
+    ///Checks amounts before any rule is applied.
+    validator: Validator,
+    ///Applied admin actions in the order they were processed.
+    audit_trail: Vec<AuditEntry>,
+    ///Fees charged per transaction type.
+    fee_schedule: FeeSchedule,
+    ///House accounts that collect every charged fee, one per currency.
+    house_accounts: BTreeMap<Currency, AccountData>,
+    ///Credit limits given to new accounts.
+    credit_limits: CreditLimits,
+    ///Charged fees in the order they were processed.
+    fee_trail: Vec<FeeEntry>,
+    ///Record an event for every applied transaction.
+    track_events: bool,
+    ///Recorded events that were not taken yet.
+    events: Vec<Event>,
+    ///Input line of the last replayed event, records up to it were already applied.
+    replayed_line: u64,
+    ///Input hash of the last replayed event, the input is checked against it before records are skipped.
+    replayed_hash: InputHash,
+    ///Output lengths of the last replayed event, rows written after it belong to records that are processed again.
+    replayed_outputs: OutputLengths,
+}
+
+impl Engine {
+    ///Create a new engine with no accounts and no stored transactions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///Set how disputes over withdrawals are handled.
+    pub fn with_dispute_policy(mut self, dispute_policy: DisputePolicy) -> Self {
+        self.dispute_policy = dispute_policy;
+        self
+    }
+
+    ///Set what a locked account still accepts.
+    pub fn with_lock_policy(mut self, lock_policy: LockPolicy) -> Self {
+        self.lock_policy = lock_policy;
+        self
+    }
+
+    ///Set the fees charged per transaction type.
+    pub fn with_fee_schedule(mut self, fee_schedule: FeeSchedule) -> Self {
+        self.fee_schedule = fee_schedule;
+        self
+    }
+
+    ///Set the credit limits given to new accounts.
+    pub fn with_credit_limits(mut self, credit_limits: CreditLimits) -> Self {
+        self.credit_limits = credit_limits;
+        self
+    }
+
+    ///Record an event with the balance deltas of every applied transaction, see `take_events`.
+    pub fn with_event_tracking(mut self, track_events: bool) -> Self {
+        self.track_events = track_events;
+        self
+    }
+
+    ///Set the validator that checks amounts before any rule is applied.
+    pub fn with_validator(mut self, validator: Validator) -> Self {
+        self.validator = validator;
+        self
+    }
+
+    ///Replace accounts, stored and rejected transactions and house accounts with the state of a snapshot.
+    ///Restored accounts get the credit limits set so far. Records of a replayed log are no longer part of the state.
+    pub fn with_snapshot(mut self, snapshot: Snapshot) -> Self {
+        self.replayed_line = 0;
+        self.replayed_hash = InputHash::default();
+        self.replayed_outputs = OutputLengths::default();
+        let credit_limits = &self.credit_limits;
+        self.accounts_map = snapshot
+            .accounts
+            .into_iter()
+            .map(|account| {
+                let data = AccountData {
+                    available: account.available,
+                    held: account.held,
+                    total: account.total,
+                    locked: account.locked,
+                    credit_limit: credit_limits.limit(account.key.client),
+                };
+                (account.key, data)
+            })
+            .collect();
+        self.transactions_map = snapshot
+            .transactions
+            .into_iter()
+            .map(|entry| (entry.tx, entry.transaction))
+            .collect();
+        self.house_accounts = snapshot
+            .house_accounts
+            .into_iter()
+            .map(|(currency, fees)| {
+                let data = AccountData {
+                    available: fees,
+                    total: fees,
+                    ..AccountData::default()
+                };
+                (currency, data)
+            })
+            .collect();
+        //rejected transactions are kept apart so they cannot be disputed
+        self.rejected_map = snapshot
+            .rejected
+            .into_iter()
+            .filter_map(|data| Some((data.tx()?.clone(), data)))
+            .collect();
+        self
+    }
+
+    ///Seed accounts with opening balances, replacing existing accounts with the same client id and currency.
+    ///Seeded accounts get the credit limits set so far.
+    pub fn with_accounts(mut self, accounts: BTreeMap<AccountKey, AccountData>) -> Self {
+        for (key, data) in accounts {
+            let credit_limit = self.credit_limits.limit(key.client);
+            self.accounts_map.insert(
+                key,
+                AccountData {
+                    credit_limit,
+                    ..data
+                },
+            );
+        }
+        self
+    }
+
+    ///Capture accounts, stored and rejected transactions and house accounts in a snapshot.
+    pub fn snapshot(&self) -> Snapshot {
+        let accounts = self
+            .accounts_map
+            .iter()
+            .map(|(key, data)| SnapshotAccount {
+                key: key.clone(),
+                available: data.available,
+                held: data.held,
+                total: data.total,
+                locked: data.locked,
+            })
+            .collect();
+
+        //order transactions by id so the same state always gives the same snapshot
+        let mut transactions: Vec<SnapshotTransaction> = self
+            .transactions_map
+            .iter()
+            .map(|(tx, transaction)| SnapshotTransaction {
+                tx: tx.clone(),
+                transaction: transaction.clone(),
+            })
+            .collect();
+        transactions.sort_by(|a, b| a.tx.cmp(&b.tx));
+
+        let house_accounts = self
+            .house_accounts
+            .iter()
+            .map(|(currency, data)| (currency.clone(), data.total))
+            .collect();
+
+        let mut rejected: Vec<Transaction> = self.rejected_map.values().cloned().collect();
+        rejected.sort_by(|a, b| a.tx().cmp(&b.tx()));
+
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            accounts,
+            transactions,
+            house_accounts,
+            rejected,
+        }
+    }
+
+    ///Convert a raw record to a transaction and process it. Records that cannot be converted are rejected.
+    pub fn process_record(&mut self, record: TransactionRecord) -> Outcome {
+        let currency = record
+            .currency
+            .as_deref()
+            .map(Currency::new)
+            .transpose()
+            .map(Option::unwrap_or_default);
+        match currency.and_then(|currency| Ok((Transaction::try_from(record)?, currency))) {
+            Ok((data, currency)) => self.process_in(data, currency),
+            Err(e) => Outcome::Rejected(e.into()),
+        }
+    }
+
+    ///Process one transaction without a currency and return it's outcome.
+    pub fn process(&mut self, data: Transaction) -> Outcome {
+        self.process_in(data, Currency::default())
+    }
+
+    ///Process one transaction in a currency and return it's outcome.
+    ///Disputes, resolves and chargebacks are applied in the currency of the referenced transaction instead.
+    pub fn process_in(&mut self, data: Transaction, currency: Currency) -> Outcome {
+        let transactions_map = &mut self.transactions_map;
+        let rejected_map = &self.rejected_map;
+        let dispute_policy = self.dispute_policy;
+
+        //disputes resolve against the currency of the original transaction
+        let currency = match &data {
+            Transaction::Dispute { tx, .. }
+            | Transaction::Resolve { tx, .. }
+            | Transaction::Chargeback { tx, .. } => match transactions_map.get(tx) {
+                Some(transaction) => transaction.currency.clone(),
+                None => currency,
+            },
+            _ => currency,
+        };
+        let key = AccountKey::new(data.client(), currency.clone());
+
+        //accounts this transaction can touch, their state is kept so applied changes can be recorded
+        let mut touched = vec![key.clone()];
+        match &data {
+            Transaction::Transfer { to_client, .. } => {
+                touched.push(AccountKey::new(*to_client, currency.clone()));
+            }
+            Transaction::Dispute { tx, .. }
+            | Transaction::Resolve { tx, .. }
+            | Transaction::Chargeback { tx, .. } => {
+                if let Some(to_client) = transactions_map.get(tx).and_then(|t| t.to_client) {
+                    touched.push(AccountKey::new(to_client, currency.clone()));
+                }
+            }
+            _ => {}
+        }
+        let accounts_map = &self.accounts_map;
+        //locks belong to the client, so locking or unlocking touches every account of the client
+        if let Transaction::Freeze { .. }
+        | Transaction::Unlock { .. }
+        | Transaction::Chargeback { .. } = &data
+        {
+            for other in client_keys(accounts_map, data.client()) {
+                if !touched.contains(other) {
+                    touched.push(other.clone());
+                }
+            }
+        }
+        let before: Vec<Option<AccountData>> = if self.track_events {
+            touched
+                .iter()
+                .map(|key| accounts_map.get(key).cloned())
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let (client, col_type, tx) = (data.client(), data.col_type(), data.tx().cloned());
+
+        //a transfer also needs the receiving client to accept deposits, this client is created only when it is applied
+        //and disputing a transfer moves the receiving client's funds, so it has to accept the dispute action as well
+        let recipient = match &data {
+            Transaction::Transfer { to_client, .. } => Some((*to_client, TransactionType::Deposit)),
+            Transaction::Dispute { tx, .. }
+            | Transaction::Resolve { tx, .. }
+            | Transaction::Chargeback { tx, .. } => transactions_map
+                .get(tx)
+                .filter(|transaction| transaction.client_id == data.client())
+                .and_then(|transaction| transaction.to_client)
+                .map(|to_client| (to_client, data.col_type())),
+            _ => None,
+        };
+        let lock_policy = self.lock_policy;
+        let recipient_locked = recipient.and_then(|(to_client, col_type)| {
+            Some(to_client)
+                .filter(|_| client_locked(accounts_map, to_client) && !lock_policy.allows(col_type))
+        });
+        let sender_locked = client_locked(accounts_map, client);
+        //the credit limit is shared by every account of the client
+        let credit = remaining_credit(accounts_map, client, self.credit_limits.limit(client));
+        //the receiving client's side is checked before anything is changed, so it can be applied after the sending side
+        let recipient_change = match &data {
+            Transaction::Transfer {
+                to_client, amount, ..
+            } => Some((*to_client, deposit_change(amount.value()))),
+            _ => recipient.and_then(|(to_client, _)| {
+                let transaction = transactions_map.get(data.tx()?)?;
+                Some((
+                    to_client,
+                    dispute_change(&data, transaction.amount.value(), false),
+                ))
+            }),
+        };
+        let recipient_overflows = recipient_change.map_or(false, |(to_client, change)| {
+            let recipient_key = AccountKey::new(to_client, currency.clone());
+            let mut recipient_data = accounts_map
+                .get(&recipient_key)
+                .cloned()
+                .unwrap_or_default();
+            add_funds(&mut recipient_data, &[change]) != Outcome::Applied
+        });
+        let house_accounts = &self.house_accounts;
+        let house_overflows = |fee: Decimal| {
+            house_accounts
+                .get(&currency)
+                .map_or(false, |house_account| {
+                    house_account.total.checked_add(fee).is_none()
+                })
+        };
+        //receiving client of a disputed transfer, it's funds are held instead of the sending client's
+        let mut recipient_dispute = None;
+        //rejected transaction that was kept, it is logged so replay keeps it as well
+        let mut rejected = None;
+        //fee charged to the client when the transaction is applied
+        let mut fee = Decimal::ZERO;
+
+        //admin actions are allowed on locked accounts, this is how they get unlocked
+        //they only create an account when they have to, see `apply_admin`
+        let outcome = if data.is_admin() {
+            let outcome = match self.validator.validate(&data) {
+                Err(e) => Outcome::Rejected(e.into()),
+                Ok(()) => apply_admin(&mut self.accounts_map, &self.credit_limits, &key, &data),
+            };
+            if outcome == Outcome::Applied {
+                self.audit_trail.push(AuditEntry {
+                    currency: currency.clone(),
+                    ..AuditEntry::from(&data)
+                });
+            }
+            outcome
+        } else {
+            //if client id is not found then create a new client
+            let current_client_data =
+                account_entry(&mut self.accounts_map, &self.credit_limits, key.clone());
+
+            //guard for invalid amounts, these never reach the rules below
+            //and guard for locked accounts, the lock policy decides what they still accept
+            if let Err(e) = self.validator.validate(&data) {
+                Outcome::Rejected(e.into())
+            } else if sender_locked && !self.lock_policy.allows(data.col_type()) {
+                Outcome::Rejected(RejectReason::AccountLocked(data.client()))
+            } else if let Some(to_client) = recipient_locked {
+                Outcome::Rejected(RejectReason::AccountLocked(to_client))
+            } else if let Transaction::Deposit { tx, amount, .. }
+            | Transaction::Withdrawal { tx, amount, .. }
+            | Transaction::Transfer { tx, amount, .. } = &data
+            {
+                //guard for not overwritting transactions with a previously used id
+                //this transactions should not be in our storage for now
+                if transactions_map.contains_key(tx) {
+                    Outcome::Rejected(RejectReason::DuplicateTransaction(tx.clone()))
+                } else {
+                    //a fee too large to be stored is rejected like any other overflow
+                    let withdrawal_fee = match data {
+                        Transaction::Withdrawal { .. } => {
+                            self.fee_schedule.withdrawal_fee(amount.value())
+                        }
+                        _ => Some(Decimal::ZERO),
+                    };
+                    fee = withdrawal_fee.unwrap_or_default();
+                    if withdrawal_fee.is_none() || recipient_overflows || house_overflows(fee) {
+                        Outcome::Rejected(RejectReason::Overflow)
+                    } else {
+                        apply_movement(
+                            current_client_data,
+                            data.col_type(),
+                            amount.value(),
+                            fee,
+                            credit,
+                        )
+                    }
+                }
+            } else {
+                //get the underlying transaction for doing a dispute, resolve or chargeback
+                match dispute_target(transactions_map, rejected_map, &data, dispute_policy) {
+                    Ok((transaction, next_state)) => {
+                        let amount = transaction.amount.value();
+                        let reversed = transaction.col_type == TransactionType::Withdrawal
+                            && dispute_policy == DisputePolicy::Reversed;
+
+                        //charge the chargeback fee, this can make the balance negative
+                        //the client is locked once the chargeback is applied
+                        if let Transaction::Chargeback { .. } = data {
+                            fee = self.fee_schedule.chargeback_fee();
+                        }
+                        let charged = (-fee, Decimal::ZERO, -fee);
+
+                        let outcome = match transaction.to_client {
+                            _ if recipient_overflows || house_overflows(fee) => {
+                                Outcome::Rejected(RejectReason::Overflow)
+                            }
+                            //a transfer is disputed as a single unit, funds are held on the receiving client
+                            //and a chargeback returns them to the sending client
+                            Some(to_client) => {
+                                let returned = match data {
+                                    Transaction::Chargeback { .. } => deposit_change(amount),
+                                    _ => (Decimal::ZERO, Decimal::ZERO, Decimal::ZERO),
+                                };
+                                let outcome = add_funds(current_client_data, &[returned, charged]);
+                                if outcome == Outcome::Applied {
+                                    recipient_dispute = Some((to_client, amount));
+                                }
+                                outcome
+                            }
+                            None => add_funds(
+                                current_client_data,
+                                &[dispute_change(&data, amount, reversed), charged],
+                            ),
+                        };
+
+                        //resolved and charged back disputes cannot be reopened
+                        if outcome == Outcome::Applied {
+                            transaction.dispute_state = next_state;
+                        }
+                        outcome
+                    }
+                    Err(reason) => Outcome::Rejected(reason),
+                }
+            }
+        };
+
+        //add this transaction to our storage
+        //this should be the last step to avoid getting erronous results
+        //we should only add transactions that are withdrawal or deposit
+        //it doesn't make sense that there would be disputes over resolves or resolves over resolves, etc
+        //only applied transactions can be disputed, rejected ones are kept apart for reporting
+        if let (Outcome::Applied, Some(tx)) = (&outcome, data.tx()) {
+            if fee > Decimal::ZERO {
+                let house_account = self.house_accounts.entry(currency.clone()).or_default();
+                house_account.available += fee;
+                house_account.total += fee;
+                self.fee_trail.push(FeeEntry {
+                    client: data.client(),
+                    tx: tx.clone(),
+                    col_type: data.col_type(),
+                    fee,
+                    currency: currency.clone(),
+                });
+            }
+        }
+
+        //lock the client when chargeback occurs, every account of the client is locked
+        if let (Outcome::Applied, Transaction::Chargeback { .. }) = (&outcome, &data) {
+            set_client_lock(&mut self.accounts_map, client, true);
+        }
+
+        if let Some((to_client, amount)) = recipient_dispute {
+            let recipient_key = AccountKey::new(to_client, currency.clone());
+            let recipient_data =
+                account_entry(&mut self.accounts_map, &self.credit_limits, recipient_key);
+            //this was checked for overflow before the sending side was changed
+            add_funds(recipient_data, &[dispute_change(&data, amount, false)]);
+        }
+
+        if let Transaction::Deposit { client, tx, amount }
+        | Transaction::Withdrawal { client, tx, amount }
+        | Transaction::Transfer {
+            client, tx, amount, ..
+        } = &data
+        {
+            let to_client = match &data {
+                Transaction::Transfer { to_client, .. } => Some(*to_client),
+                _ => None,
+            };
+
+            match outcome {
+                //applied transactions never collide because duplicate ids are rejected
+                Outcome::Applied => {
+                    //credit the receiving client of a transfer, the sending client was already debited
+                    //and this was checked for overflow before that
+                    if let Some(to_client) = to_client {
+                        let recipient_key = AccountKey::new(to_client, currency.clone());
+                        let recipient_data = account_entry(
+                            &mut self.accounts_map,
+                            &self.credit_limits,
+                            recipient_key,
+                        );
+                        add_funds(recipient_data, &[deposit_change(amount.value())]);
+                    }
+
+                    transactions_map.insert(
+                        tx.clone(),
+                        StoredTransaction {
+                            client_id: *client,
+                            to_client,
+                            col_type: data.col_type(),
+                            currency: currency.clone(),
+                            amount: *amount,
+                            dispute_state: DisputeState::None,
+                        },
+                    );
+                }
+                //keep the first rejected transaction for an id, a later retry may still be applied
+                Outcome::Rejected(_) => {
+                    if let Entry::Vacant(entry) = self.rejected_map.entry(tx.clone()) {
+                        rejected = Some(entry.insert(data).clone());
+                    }
+                }
+            }
+        }
+
+        //record what the transaction changed so it can be replayed, a rejected transaction only changes the state
+        //when it is kept as rejected or created an account
+        let applied = outcome == Outcome::Applied;
+        let accounts_map = &self.accounts_map;
+        let created = || {
+            touched
+                .iter()
+                .zip(&before)
+                .any(|(key, before)| before.is_none() && accounts_map.contains_key(key))
+        };
+        if self.track_events && (applied || rejected.is_some() || created()) {
+            //accounts that were not created are left out so replay does not create them
+            let deltas = touched
+                .into_iter()
+                .zip(before)
+                .filter_map(|(key, before)| {
+                    let after = accounts_map.get(&key)?;
+                    let before = before.unwrap_or_default();
+                    Some(AccountDelta {
+                        key,
+                        available: after.available - before.available,
+                        held: after.held - before.held,
+                        total: after.total - before.total,
+                        locked: after.locked,
+                    })
+                })
+                .collect();
+            let stored = tx.filter(|_| applied).and_then(|tx| {
+                transactions_map
+                    .get(&tx)
+                    .map(|transaction| SnapshotTransaction {
+                        tx: tx.clone(),
+                        transaction: transaction.clone(),
+                    })
+            });
+
+            //the input line and hash are not known here, they are set by whoever feeds records to the engine
+            self.events.push(Event {
+                line: 0,
+                input_hash: 0,
+                client,
+                col_type,
+                deltas,
+                stored,
+                rejected,
+                fee: Some((currency, fee)).filter(|(_, fee)| applied && *fee > Decimal::ZERO),
+                outputs: None,
+            });
+        }
+
+        outcome
+    }
+
+    ///Take the recorded events out of the engine, leaving none behind.
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    ///Rebuild state by replaying an event log on top of the current state. Returns the input line of the last
+    ///replayed event, records up to it were already applied and are skipped when the same input is processed,
+    ///see `replayed_hash`.
+    ///No rule is checked, events only hold what was already decided. A last line without a newline was cut by
+    ///a crash and is skipped.
+    pub fn replay<R: BufRead>(&mut self, mut input: R) -> crate::Result<u64> {
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            if input.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+                break;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let event: Event = serde_json::from_str(&line)?;
+            //events are in input order, so the last one tells how far the input was processed
+            self.replayed_line = event.line;
+            self.replayed_hash = InputHash::from_value(event.input_hash);
+            self.replayed_outputs = event.outputs.clone().unwrap_or_default();
+            self.apply_event(event);
+        }
+
+        Ok(self.replayed_line)
+    }
+
+    ///Input line of the last replayed event, zero when nothing was replayed.
+    pub fn replayed_line(&self) -> u64 {
+        self.replayed_line
+    }
+
+    ///Hash of the input up to the end of the record on the replayed line.
+    pub fn replayed_hash(&self) -> InputHash {
+        self.replayed_hash
+    }
+
+    ///Lengths of the outputs once the record on the replayed line was written, see `checkpoint::open_output`.
+    pub fn replayed_outputs(&self) -> &OutputLengths {
+        &self.replayed_outputs
+    }
+
+    ///Apply the changes of a logged event.
+    fn apply_event(&mut self, event: Event) {
+        for delta in event.deltas {
+            let data = account_entry(&mut self.accounts_map, &self.credit_limits, delta.key);
+            data.available += delta.available;
+            data.held += delta.held;
+            data.total += delta.total;
+            data.locked = delta.locked;
+        }
+
+        if let Some(entry) = event.stored {
+            self.transactions_map.insert(entry.tx, entry.transaction);
+        }
+
+        if let Some(data) = event.rejected {
+            if let Some(tx) = data.tx() {
+                self.rejected_map.insert(tx.clone(), data);
+            }
+        }
+
+        if let Some((currency, fee)) = event.fee {
+            let house_account = self.house_accounts.entry(currency).or_default();
+            house_account.available += fee;
+            house_account.total += fee;
+        }
+    }
+
+    ///Current account state indexed by client id and currency.
+    pub fn accounts(&self) -> &BTreeMap<AccountKey, AccountData> {
+        &self.accounts_map
+    }
+
+    ///Deposits, withdrawals and transfers that were rejected, indexed by transaction id.
+    pub fn rejected_transactions(&self) -> &HashMap<TxId, Transaction> {
+        &self.rejected_map
+    }
+
+    ///Applied admin actions in the order they were processed.
+    pub fn audit_trail(&self) -> &[AuditEntry] {
+        &self.audit_trail
+    }
+
+    ///Take the applied admin actions out of the engine, leaving it's audit trail empty.
+    pub fn take_audit_trail(&mut self) -> Vec<AuditEntry> {
+        std::mem::take(&mut self.audit_trail)
+    }
+
+    ///House accounts that collect every charged fee, indexed by currency.
+    pub fn house_accounts(&self) -> &BTreeMap<Currency, AccountData> {
+        &self.house_accounts
+    }
+
+    ///Charged fees in the order they were processed.
+    pub fn fee_trail(&self) -> &[FeeEntry] {
+        &self.fee_trail
+    }
+
+    ///Take the charged fees out of the engine, leaving it's fee trail empty.
+    pub fn take_fee_trail(&mut self) -> Vec<FeeEntry> {
+        std::mem::take(&mut self.fee_trail)
+    }
+
+    ///Number of accounts and stored or rejected transactions, the size of a snapshot grows with it.
+    pub(crate) fn state_len(&self) -> usize {
+        self.accounts_map.len() + self.transactions_map.len() + self.rejected_map.len()
+    }
+
+    ///Consume the engine and return the account state.
+    pub fn into_accounts(self) -> BTreeMap<AccountKey, AccountData> {
+        self.accounts_map
+    }
+}
+
+///Apply an unlock, freeze or adjustment.
+///Unlocks and freezes change every account of the client and only create an account for a client without any.
+///An adjustment without a currency is rejected when the client only has accounts in other currencies.
+fn apply_admin(
+    accounts_map: &mut BTreeMap<AccountKey, AccountData>,
+    credit_limits: &CreditLimits,
+    key: &AccountKey,
+    data: &Transaction,
+) -> Outcome {
+    let has_accounts = client_keys(accounts_map, key.client).next().is_some();
+
+    match data {
+        //there is nothing to unlock on a client without accounts
+        Transaction::Unlock { .. } if !has_accounts => {
+            return Outcome::Rejected(RejectReason::UnknownClient(key.client));
+        }
+        Transaction::Unlock { .. } | Transaction::Freeze { .. } => {
+            //a lock on a new client is kept on it's account
+            if !has_accounts {
+                account_entry(accounts_map, credit_limits, key.clone());
+            }
+            let locked = matches!(data, Transaction::Freeze { .. });
+            set_client_lock(accounts_map, key.client, locked);
+        }
+        Transaction::Adjust { amount, .. } => {
+            if key.currency.is_empty() && has_accounts && !accounts_map.contains_key(key) {
+                return Outcome::Rejected(RejectReason::MissingCurrency);
+            }
+            //corrections are not limited by available funds
+            let current_client_data = account_entry(accounts_map, credit_limits, key.clone());
+            return add_funds(current_client_data, &[deposit_change(*amount)]);
+        }
+        _ => {}
+    }
+
+    Outcome::Applied
+}
+
+///Keys of every account of a client, ordered by currency.
+fn client_keys(
+    accounts_map: &BTreeMap<AccountKey, AccountData>,
+    client: ClientId,
+) -> impl Iterator<Item = &AccountKey> {
+    accounts_map
+        .range(AccountKey::new(client, Currency::default())..)
+        .map(|(key, _)| key)
+        .take_while(move |key| key.client == client)
+}
+
+///Check if a client is locked, a lock on any of it's accounts locks the client.
+fn client_locked(accounts_map: &BTreeMap<AccountKey, AccountData>, client: ClientId) -> bool {
+    accounts_map
+        .range(AccountKey::new(client, Currency::default())..)
+        .take_while(|(key, _)| key.client == client)
+        .any(|(_, data)| data.locked)
+}
+
+///Lock or unlock every account of a client.
+fn set_client_lock(
+    accounts_map: &mut BTreeMap<AccountKey, AccountData>,
+    client: ClientId,
+    locked: bool,
+) {
+    for (_, data) in accounts_map
+        .range_mut(AccountKey::new(client, Currency::default())..)
+        .take_while(|(key, _)| key.client == client)
+    {
+        data.locked = locked;
+    }
+}
+
+///Get the account of a client in a currency, a new account gets the client's credit limit and lock.
+fn account_entry<'a>(
+    accounts_map: &'a mut BTreeMap<AccountKey, AccountData>,
+    credit_limits: &CreditLimits,
+    key: AccountKey,
+) -> &'a mut AccountData {
+    let credit_limit = credit_limits.limit(key.client);
+    let locked = !accounts_map.contains_key(&key) && client_locked(accounts_map, key.client);
+    accounts_map.entry(key).or_insert_with(|| AccountData {
+        locked,
+        ..AccountData::with_credit_limit(credit_limit)
+    })
+}
+
+///Change of available, held and total funds.
+type Funds = (Decimal, Decimal, Decimal);
+
+///Change of a deposit, or of an adjustment when the amount is negative.
+fn deposit_change(amount: Decimal) -> Funds {
+    (amount, Decimal::ZERO, amount)
+}
+
+///Add changes to the funds of an account one after another.
+///Nothing is changed when any balance would overflow, the largest amounts are valid so large balances can overflow.
+fn add_funds(current_client_data: &mut AccountData, changes: &[Funds]) -> Outcome {
+    let mut funds = (
+        current_client_data.available,
+        current_client_data.held,
+        current_client_data.total,
+    );
+    for (available, held, total) in changes {
+        funds = match (
+            funds.0.checked_add(*available),
+            funds.1.checked_add(*held),
+            funds.2.checked_add(*total),
+        ) {
+            (Some(available), Some(held), Some(total)) => (available, held, total),
+            _ => return Outcome::Rejected(RejectReason::Overflow),
+        };
+    }
+
+    current_client_data.available = funds.0;
+    current_client_data.held = funds.1;
+    current_client_data.total = funds.2;
+    Outcome::Applied
+}
+
+///Apply a deposit, withdrawal or the sending side of a transfer to the client account.
+///The fee is debited together with the amount, the credit is what is left of the client's credit limit.
+fn apply_movement(
+    current_client_data: &mut AccountData,
+    col_type: TransactionType,
+    amount: Decimal,
+    fee: Decimal,
+    credit: Decimal,
+) -> Outcome {
+    if col_type == TransactionType::Deposit {
+        //deposits are never charged a fee
+        //deposit is a credit to the client's asset account, meaning it should increase the available
+        //and total funds of the client account
+        add_funds(current_client_data, &[deposit_change(amount)])
+    } else {
+        // withdraw and transfer are a debit to the client's asset account, meaning it should decrease the available and
+        // total funds of the client account
+
+        //If a client does not have sufficient available funds the withdrawal should fail and the total amount
+        //of funds should not change, the fee is part of the funds needed
+        //clients with a credit limit can go below zero until the limit is used up across all of their accounts,
+        //funds that overflow cover any debit
+        let debit = match amount.checked_add(fee) {
+            Some(debit) => debit,
+            None => return Outcome::Rejected(RejectReason::Overflow),
+        };
+        let funds = current_client_data
+            .available
+            .max(Decimal::ZERO)
+            .checked_add(credit);
+        if funds.map_or(false, |funds| debit > funds) {
+            return Outcome::Rejected(if current_client_data.credit_limit.is_zero() {
+                RejectReason::InsufficientFunds
+            } else {
+                RejectReason::CreditLimitExceeded
+            });
+        }
+        add_funds(current_client_data, &[(-debit, Decimal::ZERO, -debit)])
+    }
+}
+
+///Change of the client account holding the disputed funds by a dispute, resolve or chargeback.
+///Reversed semantics hold a credit for a withdrawal instead of debiting the client a second time.
+fn dispute_change(data: &Transaction, amount: Decimal, reversed: bool) -> Funds {
+    match (data, reversed) {
+        //move funds from available to held
+        (Transaction::Dispute { .. }, false) => (-amount, amount, Decimal::ZERO),
+        //hold a credit for funds that already left the account
+        (Transaction::Dispute { .. }, true) => (Decimal::ZERO, amount, amount),
+        //release held funds
+        (Transaction::Resolve { .. }, false) => (amount, -amount, Decimal::ZERO),
+        //the withdrawal stands so the held credit is dropped
+        (Transaction::Resolve { .. }, true) => (Decimal::ZERO, -amount, -amount),
+        //on chargeback take funds from held an total accounts
+        (_, false) => (Decimal::ZERO, -amount, -amount),
+        //the withdrawal is reversed so the held credit is returned to the client
+        (_, true) => (amount, -amount, Decimal::ZERO),
+    }
+}
+
+///Find the stored transaction referenced by a dispute, resolve or chargeback and the dispute state it moves to.
+fn dispute_target<'a>(
+    transactions_map: &'a mut HashMap<TxId, StoredTransaction>,
+    rejected_map: &HashMap<TxId, Transaction>,
+    data: &Transaction,
+    dispute_policy: DisputePolicy,
+) -> Result<(&'a mut StoredTransaction, DisputeState), RejectReason> {
+    let transaction_id = data
+        .tx()
+        .cloned()
+        .ok_or(RejectReason::MissingTransactionId)?;
+
+    //guard for references to transactions that were rejected and never moved funds
+    if !transactions_map.contains_key(&transaction_id) && rejected_map.contains_key(&transaction_id)
+    {
+        return Err(RejectReason::NotApplied(transaction_id));
+    }
+
+    let transaction = transactions_map
+        .get_mut(&transaction_id)
+        .ok_or_else(|| RejectReason::UnknownTransaction(transaction_id.clone()))?;
+
+    //guard for references to a transaction id that does not belong to the current client id
+    if transaction.client_id != data.client() {
+        return Err(RejectReason::ClientMismatch(transaction_id));
+    }
+
+    //guard for withdrawal disputes when the policy does not allow them
+    if data.col_type() == TransactionType::Dispute
+        && transaction.col_type == TransactionType::Withdrawal
+        && dispute_policy == DisputePolicy::Reject
+    {
+        return Err(RejectReason::WithdrawalDispute(transaction_id));
+    }
+
+    //guard for transitions the dispute lifecycle does not allow
+    match transaction.dispute_state.next(&data.col_type()) {
+        Some(next_state) => Ok((transaction, next_state)),
+        None if transaction.dispute_state.is_terminal() => {
+            Err(RejectReason::DisputeClosed(transaction_id))
+        }
+        None if transaction.dispute_state == DisputeState::Disputed => {
+            Err(RejectReason::AlreadyDisputed(transaction_id))
+        }
+        None => Err(RejectReason::NotDisputed(transaction_id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::Amount;
+    use crate::structs::ClientIdValue;
+
+    fn client(id: ClientIdValue) -> ClientId {
+        ClientId(id)
+    }
+
+    fn tx(id: u32) -> TxId {
+        TxId(id.to_string().parse().unwrap())
+    }
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    fn amount(value: &str) -> Amount {
+        Amount::try_from(dec(value)).unwrap()
+    }
+
+    fn deposit(id: ClientIdValue, tx_id: u32, value: &str) -> Transaction {
+        Transaction::Deposit {
+            client: client(id),
+            tx: tx(tx_id),
+            amount: amount(value),
+        }
+    }
+
+    fn withdrawal(id: ClientIdValue, tx_id: u32, value: &str) -> Transaction {
+        Transaction::Withdrawal {
+            client: client(id),
+            tx: tx(tx_id),
+            amount: amount(value),
+        }
+    }
+
+    fn transfer(id: ClientIdValue, to_id: ClientIdValue, tx_id: u32, value: &str) -> Transaction {
+        Transaction::Transfer {
+            client: client(id),
+            to_client: client(to_id),
+            tx: tx(tx_id),
+            amount: amount(value),
+        }
+    }
+
+    fn dispute(id: ClientIdValue, tx_id: u32) -> Transaction {
+        Transaction::Dispute {
+            client: client(id),
+            tx: tx(tx_id),
+        }
+    }
+
+    fn resolve(id: ClientIdValue, tx_id: u32) -> Transaction {
+        Transaction::Resolve {
+            client: client(id),
+            tx: tx(tx_id),
+        }
+    }
+
+    fn chargeback(id: ClientIdValue, tx_id: u32) -> Transaction {
+        Transaction::Chargeback {
+            client: client(id),
+            tx: tx(tx_id),
+        }
+    }
+
+    fn unlock(id: ClientIdValue) -> Transaction {
+        Transaction::Unlock { client: client(id) }
+    }
+
+    fn freeze(id: ClientIdValue) -> Transaction {
+        Transaction::Freeze { client: client(id) }
+    }
+
+    fn adjust(id: ClientIdValue, value: &str, reason: &str) -> Transaction {
+        Transaction::Adjust {
+            client: client(id),
+            amount: dec(value),
+            reason: reason.to_string(),
+        }
+    }
+
+    ///Account of a client in a currency, panics when it does not exist.
+    fn account<'a>(engine: &'a Engine, id: ClientIdValue, currency: &str) -> &'a AccountData {
+        &engine.accounts()[&AccountKey::new(client(id), Currency::new(currency).unwrap())]
+    }
+
+    ///Available, held and total funds of an account.
+    fn funds(engine: &Engine, id: ClientIdValue) -> (Decimal, Decimal, Decimal) {
+        let data = account(engine, id, "");
+        (data.available, data.held, data.total)
+    }
+
+    #[test]
+    fn deposit_and_withdrawal_move_available_and_total() {
+        let mut engine = Engine::new();
+        assert_eq!(engine.process(deposit(1, 1, "10")), Outcome::Applied);
+        assert_eq!(engine.process(withdrawal(1, 2, "4.5")), Outcome::Applied);
+
+        assert_eq!(funds(&engine, 1), (dec("5.5"), dec("0"), dec("5.5")));
+    }
+
+    #[test]
+    fn withdrawal_over_available_funds_is_rejected() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, "10"));
+
+        assert_eq!(
+            engine.process(withdrawal(1, 2, "10.0001")),
+            Outcome::Rejected(RejectReason::InsufficientFunds)
+        );
+        assert_eq!(funds(&engine, 1), (dec("10"), dec("0"), dec("10")));
+    }
+
+    #[test]
+    fn deposit_that_would_overflow_the_balance_is_rejected() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, "50000000000000000000000000000"));
+
+        assert_eq!(
+            engine.process(deposit(1, 2, "50000000000000000000000000000")),
+            Outcome::Rejected(RejectReason::Overflow)
+        );
+        assert_eq!(
+            funds(&engine, 1),
+            (
+                dec("50000000000000000000000000000"),
+                dec("0"),
+                dec("50000000000000000000000000000")
+            )
+        );
+    }
+
+    #[test]
+    fn reused_transaction_id_is_rejected() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, "10"));
+
+        assert_eq!(
+            engine.process(deposit(1, 1, "5")),
+            Outcome::Rejected(RejectReason::DuplicateTransaction(tx(1)))
+        );
+    }
+
+    #[test]
+    fn dispute_holds_and_resolve_releases_funds() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, "10"));
+
+        assert_eq!(engine.process(dispute(1, 1)), Outcome::Applied);
+        assert_eq!(funds(&engine, 1), (dec("0"), dec("10"), dec("10")));
+        assert_eq!(engine.process(resolve(1, 1)), Outcome::Applied);
+        assert_eq!(funds(&engine, 1), (dec("10"), dec("0"), dec("10")));
+    }
+
+    #[test]
+    fn rejected_transaction_cannot_be_disputed() {
+        let mut engine = Engine::new();
+        engine.process(withdrawal(1, 1, "5"));
+
+        assert_eq!(
+            engine.process(dispute(1, 1)),
+            Outcome::Rejected(RejectReason::NotApplied(tx(1)))
+        );
+        assert!(engine.rejected_transactions().contains_key(&tx(1)));
+        //the id of a rejected transaction can still be used by a later one
+        assert_eq!(engine.process(deposit(1, 1, "5")), Outcome::Applied);
+        assert_eq!(engine.process(dispute(1, 1)), Outcome::Applied);
+    }
+
+    #[test]
+    fn closed_dispute_cannot_be_reopened() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, "10"));
+        engine.process(deposit(1, 2, "10"));
+        engine.process(dispute(1, 1));
+        engine.process(resolve(1, 1));
+        engine.process(dispute(1, 2));
+
+        assert_eq!(
+            engine.process(dispute(1, 2)),
+            Outcome::Rejected(RejectReason::AlreadyDisputed(tx(2)))
+        );
+        for transaction in [dispute(1, 1), resolve(1, 1), chargeback(1, 1)] {
+            assert_eq!(
+                engine.process(transaction),
+                Outcome::Rejected(RejectReason::DisputeClosed(tx(1)))
+            );
+        }
+        assert_eq!(funds(&engine, 1), (dec("10"), dec("10"), dec("20")));
+    }
+
+    ///Engine with a deposit of 10 and a withdrawal of 4 by client 1, disputed under the given policy.
+    fn disputed_withdrawal(dispute_policy: DisputePolicy) -> (Engine, Outcome) {
+        let mut engine = Engine::new().with_dispute_policy(dispute_policy);
+        engine.process(deposit(1, 1, "10"));
+        engine.process(withdrawal(1, 2, "4"));
+        let outcome = engine.process(dispute(1, 2));
+        (engine, outcome)
+    }
+
+    #[test]
+    fn legacy_policy_disputes_a_withdrawal_like_a_deposit() {
+        let (mut engine, outcome) = disputed_withdrawal(DisputePolicy::default());
+
+        assert_eq!(outcome, Outcome::Applied);
+        assert_eq!(funds(&engine, 1), (dec("2"), dec("4"), dec("6")));
+        engine.process(chargeback(1, 2));
+        assert_eq!(funds(&engine, 1), (dec("2"), dec("0"), dec("2")));
+    }
+
+    #[test]
+    fn reject_policy_refuses_withdrawal_disputes() {
+        let (mut engine, outcome) = disputed_withdrawal(DisputePolicy::Reject);
+
+        assert_eq!(
+            outcome,
+            Outcome::Rejected(RejectReason::WithdrawalDispute(tx(2)))
+        );
+        assert_eq!(funds(&engine, 1), (dec("6"), dec("0"), dec("6")));
+        //deposits can still be disputed
+        assert_eq!(engine.process(dispute(1, 1)), Outcome::Applied);
+    }
+
+    #[test]
+    fn reversed_policy_holds_a_credit_for_the_withdrawn_funds() {
+        let (mut engine, outcome) = disputed_withdrawal(DisputePolicy::Reversed);
+
+        assert_eq!(outcome, Outcome::Applied);
+        assert_eq!(funds(&engine, 1), (dec("6"), dec("4"), dec("10")));
+        engine.process(chargeback(1, 2));
+        assert_eq!(funds(&engine, 1), (dec("10"), dec("0"), dec("10")));
+
+        let (mut engine, _) = disputed_withdrawal(DisputePolicy::Reversed);
+        engine.process(resolve(1, 2));
+        assert_eq!(funds(&engine, 1), (dec("6"), dec("0"), dec("6")));
+    }
+
+    #[test]
+    fn chargeback_withdraws_held_funds_and_locks_the_client() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, "10"));
+        engine.process(deposit(1, 2, "3"));
+        engine.process(dispute(1, 1));
+
+        assert_eq!(engine.process(chargeback(1, 1)), Outcome::Applied);
+        assert_eq!(funds(&engine, 1), (dec("3"), dec("0"), dec("3")));
+        assert!(account(&engine, 1, "").locked);
+        assert_eq!(
+            engine.process(withdrawal(1, 3, "1")),
+            Outcome::Rejected(RejectReason::AccountLocked(client(1)))
+        );
+    }
+
+    #[test]
+    fn disputes_are_checked_against_the_referenced_transaction() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, "10"));
+
+        assert_eq!(
+            engine.process(dispute(2, 1)),
+            Outcome::Rejected(RejectReason::ClientMismatch(tx(1)))
+        );
+        assert_eq!(
+            engine.process(dispute(1, 9)),
+            Outcome::Rejected(RejectReason::UnknownTransaction(tx(9)))
+        );
+        assert_eq!(
+            engine.process(resolve(1, 1)),
+            Outcome::Rejected(RejectReason::NotDisputed(tx(1)))
+        );
+    }
+
+    #[test]
+    fn admin_actions_are_applied_to_locked_clients_and_audited() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, "10"));
+
+        assert_eq!(engine.process(freeze(1)), Outcome::Applied);
+        assert_eq!(
+            engine.process(deposit(1, 2, "5")),
+            Outcome::Rejected(RejectReason::AccountLocked(client(1)))
+        );
+        //corrections are not limited by available funds
+        assert_eq!(
+            engine.process(adjust(1, "-12.5", "bank error")),
+            Outcome::Applied
+        );
+        assert_eq!(engine.process(unlock(1)), Outcome::Applied);
+
+        assert_eq!(funds(&engine, 1), (dec("-2.5"), dec("0"), dec("-2.5")));
+        assert!(!account(&engine, 1, "").locked);
+        let audited: Vec<_> = engine
+            .audit_trail()
+            .iter()
+            .map(|entry| (entry.col_type, entry.amount, entry.reason.as_deref()))
+            .collect();
+        assert_eq!(
+            audited,
+            vec![
+                (TransactionType::Freeze, None, None),
+                (
+                    TransactionType::Adjust,
+                    Some(dec("-12.5")),
+                    Some("bank error")
+                ),
+                (TransactionType::Unlock, None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_freeze_creates_an_account_for_a_new_client() {
+        let mut engine = Engine::new();
+
+        assert_eq!(
+            engine.process(unlock(1)),
+            Outcome::Rejected(RejectReason::UnknownClient(client(1)))
+        );
+        assert!(engine.accounts().is_empty());
+        assert!(engine.audit_trail().is_empty());
+
+        assert_eq!(engine.process(freeze(1)), Outcome::Applied);
+        assert!(account(&engine, 1, "").locked);
+    }
+
+    ///Engine with client 1 locked by a chargeback of tx 1, tx 2 is still disputed.
+    fn locked_engine(lock_policy: LockPolicy) -> Engine {
+        let mut engine = Engine::new().with_lock_policy(lock_policy);
+        engine.process(deposit(1, 1, "10"));
+        engine.process(deposit(1, 2, "5"));
+        engine.process(dispute(1, 2));
+        engine.process(dispute(1, 1));
+        assert_eq!(engine.process(chargeback(1, 1)), Outcome::Applied);
+        engine
+    }
+
+    #[test]
+    fn locked_client_rejects_everything_by_default() {
+        let mut engine = locked_engine(LockPolicy::default());
+
+        assert_eq!(
+            engine.process(deposit(1, 3, "1")),
+            Outcome::Rejected(RejectReason::AccountLocked(client(1)))
+        );
+        assert_eq!(
+            engine.process(resolve(1, 2)),
+            Outcome::Rejected(RejectReason::AccountLocked(client(1)))
+        );
+    }
+
+    #[test]
+    fn block_withdrawals_policy_still_accepts_deposits() {
+        let mut engine = locked_engine(LockPolicy::BlockWithdrawals);
+
+        assert_eq!(engine.process(deposit(1, 3, "1")), Outcome::Applied);
+        assert_eq!(
+            engine.process(withdrawal(1, 4, "1")),
+            Outcome::Rejected(RejectReason::AccountLocked(client(1)))
+        );
+        assert_eq!(engine.process(resolve(1, 2)), Outcome::Applied);
+        assert_eq!(funds(&engine, 1), (dec("6"), dec("0"), dec("6")));
+    }
+
+    #[test]
+    fn resolve_disputes_policy_only_closes_open_disputes() {
+        let mut engine = locked_engine(LockPolicy::ResolveDisputes);
+
+        assert_eq!(
+            engine.process(deposit(1, 3, "1")),
+            Outcome::Rejected(RejectReason::AccountLocked(client(1)))
+        );
+        assert_eq!(engine.process(resolve(1, 2)), Outcome::Applied);
+        assert_eq!(
+            engine.process(dispute(1, 2)),
+            Outcome::Rejected(RejectReason::AccountLocked(client(1)))
+        );
+        assert_eq!(funds(&engine, 1), (dec("5"), dec("0"), dec("5")));
+    }
+
+    #[test]
+    fn transfer_moves_funds_to_a_new_client() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, "10"));
+
+        assert_eq!(engine.process(transfer(1, 2, 2, "4")), Outcome::Applied);
+        assert_eq!(
+            engine.process(transfer(1, 2, 3, "7")),
+            Outcome::Rejected(RejectReason::InsufficientFunds)
+        );
+
+        assert_eq!(funds(&engine, 1), (dec("6"), dec("0"), dec("6")));
+        assert_eq!(funds(&engine, 2), (dec("4"), dec("0"), dec("4")));
+    }
+
+    #[test]
+    fn transfer_to_a_locked_client_is_rejected() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, "10"));
+        engine.process(freeze(2));
+
+        assert_eq!(
+            engine.process(transfer(1, 2, 2, "4")),
+            Outcome::Rejected(RejectReason::AccountLocked(client(2)))
+        );
+        assert_eq!(funds(&engine, 1), (dec("10"), dec("0"), dec("10")));
+        assert_eq!(funds(&engine, 2), (dec("0"), dec("0"), dec("0")));
+    }
+
+    #[test]
+    fn disputed_transfer_holds_funds_of_the_receiving_client() {
+        let mut engine = Engine::new();
+        engine.process(deposit(1, 1, "10"));
+        engine.process(transfer(1, 2, 2, "4"));
+
+        assert_eq!(engine.process(dispute(1, 2)), Outcome::Applied);
+        assert_eq!(funds(&engine, 1), (dec("6"), dec("0"), dec("6")));
+        assert_eq!(funds(&engine, 2), (dec("0"), dec("4"), dec("4")));
+
+        assert_eq!(engine.process(chargeback(1, 2)), Outcome::Applied);
+        assert_eq!(funds(&engine, 1), (dec("10"), dec("0"), dec("10")));
+        assert_eq!(funds(&engine, 2), (dec("0"), dec("0"), dec("0")));
+    }
+
+    #[test]
+    fn withdrawal_fee_is_debited_and_credited_to_the_house_account() {
+        let fees = FeeSchedule {
+            withdrawal_flat: dec("0.5"),
+            withdrawal_percent: dec("50"),
+            ..FeeSchedule::default()
+        };
+        let mut engine = Engine::new().with_fee_schedule(fees);
+        engine.process(deposit(1, 1, "10"));
+
+        assert_eq!(engine.process(withdrawal(1, 2, "6")), Outcome::Applied);
+        assert_eq!(
+            engine.process(withdrawal(1, 3, "0.5")),
+            Outcome::Rejected(RejectReason::InsufficientFunds)
+        );
+        //the fee would not fit in a balance, this is rejected instead of overflowing
+        assert_eq!(
+            engine.process(withdrawal(1, 4, "60000000000000000000000000000")),
+            Outcome::Rejected(RejectReason::Overflow)
+        );
+
+        assert_eq!(funds(&engine, 1), (dec("0.5"), dec("0"), dec("0.5")));
+        assert_eq!(
+            engine.house_accounts()[&Currency::default()].total,
+            dec("3.5")
+        );
+        assert_eq!(engine.fee_trail().len(), 1);
+    }
+
+    #[test]
+    fn client_with_a_credit_limit_can_overdraw_up_to_it() {
+        let limits = CreditLimits::new()
+            .with_override(client(1), dec("5"))
+            .unwrap();
+        let mut engine = Engine::new().with_credit_limits(limits);
+        engine.process(deposit(1, 1, "10"));
+        engine.process(deposit(2, 2, "10"));
+
+        assert_eq!(engine.process(withdrawal(1, 3, "14")), Outcome::Applied);
+        assert_eq!(
+            engine.process(withdrawal(1, 4, "1.0001")),
+            Outcome::Rejected(RejectReason::CreditLimitExceeded)
+        );
+        assert_eq!(
+            engine.process(withdrawal(2, 5, "10.0001")),
+            Outcome::Rejected(RejectReason::InsufficientFunds)
+        );
+
+        assert_eq!(funds(&engine, 1), (dec("-4"), dec("0"), dec("-4")));
+        assert_eq!(
+            remaining_credit(engine.accounts(), client(1), dec("5")),
+            dec("1")
+        );
+    }
+
+    #[test]
+    fn credit_limit_is_shared_by_every_currency() {
+        let limits = CreditLimits::new().with_default(dec("5")).unwrap();
+        let mut engine = Engine::new().with_credit_limits(limits);
+        let eur = Currency::new("EUR").unwrap();
+        let usd = Currency::new("USD").unwrap();
+
+        assert_eq!(
+            engine.process_in(withdrawal(1, 1, "3"), eur.clone()),
+            Outcome::Applied
+        );
+        assert_eq!(
+            engine.process_in(withdrawal(1, 2, "2.0001"), usd.clone()),
+            Outcome::Rejected(RejectReason::CreditLimitExceeded)
+        );
+        assert_eq!(
+            engine.process_in(withdrawal(1, 3, "2"), usd),
+            Outcome::Applied
+        );
+        //funds in one currency do not free credit used in another
+        assert_eq!(
+            engine.process_in(deposit(1, 4, "10"), Currency::default()),
+            Outcome::Applied
+        );
+        assert_eq!(
+            engine.process_in(withdrawal(1, 5, "0.0001"), eur),
+            Outcome::Rejected(RejectReason::CreditLimitExceeded)
+        );
+
+        assert_eq!(account(&engine, 1, "EUR").available, dec("-3"));
+        assert_eq!(account(&engine, 1, "USD").available, dec("-2"));
+        assert_eq!(
+            remaining_credit(engine.accounts(), client(1), dec("5")),
+            dec("0")
+        );
+    }
+}
@@ -0,0 +1,234 @@
+//! Module for the crate level error type.
+
+use crate::engine::RejectReason;
+use crate::validation::AmountError;
+use std::fmt;
+
+///Result alias used across the crate.
+pub type Result<T> = std::result::Result<T, Error>;
+
+///Crate level error. Callers can match on the variant instead of the message.
+#[derive(Debug)]
+pub enum Error {
+    ///Reading or writing failed at the io level.
+    Io(std::io::Error),
+    ///CSV reader or writer failed.
+    Csv(csv::Error),
+    ///JSON reader or writer failed.
+    Json(serde_json::Error),
+    ///Input provided to the program is not valid.
+    Validation(ValidationError),
+    ///Transaction engine could not apply a rule.
+    Engine(RejectReason),
+}
+
+///Reasons for rejecting program input before any transaction is processed.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ValidationError {
+    ///Input file does not have a known extension.
+    InvalidExtension(String),
+    ///Input file does not exist.
+    FileNotFound(String),
+    ///Input path exists but is not a file.
+    NotAFile(String),
+    ///Input file metadata could not be read.
+    Metadata(String),
+    ///Command line option is not known.
+    UnknownOption(String),
+    ///Command line option was provided without it's value.
+    MissingOptionValue(String),
+    ///Command line option value is not one of the accepted values.
+    InvalidOptionValue(String, String),
+    ///Fee schedule config is not valid.
+    InvalidFeeSchedule(String),
+    ///Credit limit is negative or too precise.
+    InvalidCreditLimit(String),
+    ///Snapshot version is missing or not supported.
+    UnsupportedSnapshotVersion(String),
+    ///Opening balance total does not equal available plus held, holds the line number.
+    UnbalancedAccount(u64),
+    ///Opening balance was already provided for the client and currency, holds the line number.
+    DuplicateAccount(u64),
+    ///Opening balance has a currency that is not 3 letters, holds the line number.
+    InvalidCurrency(u64),
+    ///Checkpoints need a CSV input file, stdin and JSON cannot be resumed.
+    CheckpointUnsupported,
+    ///Checkpoint was saved for another input, holds the checkpoint path.
+    CheckpointMismatch(String),
+    ///Output of a resumed or recovered run is shorter than when the checkpoint or the last replayed event was saved,
+    ///holds the output path.
+    TruncatedOutput(String),
+    ///Replayed event log was written for another input, holds the line of the last replayed event.
+    ReplayMismatch(u64),
+    ///Replay rebuilds the state on top of the restored one, events of the restored run would be applied twice.
+    ReplayWithRestore,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Csv(e) => write!(f, "csv error: {}", e),
+            Error::Json(e) => write!(f, "json error: {}", e),
+            Error::Validation(e) => write!(f, "{}", e),
+            Error::Engine(e) => write!(f, "engine error: {}", e),
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::InvalidExtension(_) => {
+                write!(
+                    f,
+                    "Cannot continue, file extension must end with '.csv', '.json', '.ndjson' or '.jsonl', or use '--input-format'."
+                )
+            }
+            ValidationError::FileNotFound(path) => {
+                write!(f, "Cannot continue, file '{}' does not exist.", path)
+            }
+            ValidationError::NotAFile(path) => {
+                write!(f, "Cannot continue, '{}' is not a file.", path)
+            }
+            ValidationError::Metadata(path) => {
+                write!(
+                    f,
+                    "Cannot continue, cannot access file '{}' metadata.",
+                    path
+                )
+            }
+            ValidationError::UnknownOption(option) => {
+                write!(f, "Cannot continue, unknown option '{}'.", option)
+            }
+            ValidationError::MissingOptionValue(option) => {
+                write!(f, "Cannot continue, option '{}' requires a value.", option)
+            }
+            ValidationError::InvalidOptionValue(option, value) => {
+                write!(
+                    f,
+                    "Cannot continue, '{}' is not a valid value for option '{}'.",
+                    value, option
+                )
+            }
+            ValidationError::InvalidFeeSchedule(e) => {
+                write!(f, "Cannot continue, invalid fee schedule: {}.", e)
+            }
+            ValidationError::InvalidCreditLimit(limit) => {
+                write!(
+                    f,
+                    "Cannot continue, credit limit '{}' cannot be negative or have more than 4 decimal places.",
+                    limit
+                )
+            }
+            ValidationError::UnsupportedSnapshotVersion(version) => {
+                write!(
+                    f,
+                    "Cannot continue, snapshot version '{}' is not supported.",
+                    version
+                )
+            }
+            ValidationError::UnbalancedAccount(line) => {
+                write!(
+                    f,
+                    "Cannot continue, opening balance on line {} has a total that does not equal available plus held.",
+                    line
+                )
+            }
+            ValidationError::DuplicateAccount(line) => {
+                write!(
+                    f,
+                    "Cannot continue, opening balance on line {} was already provided.",
+                    line
+                )
+            }
+            ValidationError::InvalidCurrency(line) => {
+                write!(
+                    f,
+                    "Cannot continue, opening balance on line {} has a currency that is not 3 letters.",
+                    line
+                )
+            }
+            ValidationError::CheckpointUnsupported => {
+                write!(f, "Cannot continue, '--checkpoint' needs a CSV input file.")
+            }
+            ValidationError::CheckpointMismatch(path) => {
+                write!(
+                    f,
+                    "Cannot continue, checkpoint '{}' was saved for another input.",
+                    path
+                )
+            }
+            ValidationError::TruncatedOutput(path) => {
+                write!(
+                    f,
+                    "Cannot continue, output '{}' is shorter than when the checkpoint or the last replayed event was saved.",
+                    path
+                )
+            }
+            ValidationError::ReplayMismatch(line) => {
+                write!(
+                    f,
+                    "Cannot continue, the replayed event log was not written for this input, it differs up to line {}.",
+                    line
+                )
+            }
+            ValidationError::ReplayWithRestore => {
+                write!(
+                    f,
+                    "Cannot continue, '--replay' cannot be combined with '--restore', the event log can hold changes the snapshot already has."
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Csv(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::Validation(e) => Some(e),
+            Error::Engine(e) => Some(e),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl From<AmountError> for Error {
+    fn from(e: AmountError) -> Self {
+        Error::Engine(e.into())
+    }
+}
+
+impl From<RejectReason> for Error {
+    fn from(e: RejectReason) -> Self {
+        Error::Engine(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Self {
+        Error::Csv(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<ValidationError> for Error {
+    fn from(e: ValidationError) -> Self {
+        Error::Validation(e)
+    }
+}
@@ -1,35 +1,617 @@
-//! Module for exporting data to CSV.
+//! Module for exporting data to CSV and JSON.
 
+use crate::credit::remaining_credit;
+use crate::engine::AuditEntry;
+use crate::engine::RejectReason;
+use crate::error::ValidationError;
+use crate::fees::FeeEntry;
+use crate::structs::rounded;
 use crate::structs::AccountData;
+use crate::structs::AccountKey;
+use crate::structs::Currency;
+use crate::Result;
+use csv::StringRecord;
 use rust_decimal::Decimal;
-use std::collections::HashMap;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::BufWriter;
+use std::io::Write;
+use std::str::FromStr;
 
-///Function that exports accounts data to stdout.
-pub fn export_to_stdout(accounts: &HashMap<u16, AccountData>) {
-    //create header
-    println!("client,available,held,total,locked");
-    //iterate over hashmap and output result to stdout
-    //this could probably be faster if using something other than println! or format!
-    for (key, val) in accounts {
-        println!(
-            "{},{},{},{},{}",
-            key,
-            if val.available == Decimal::ZERO {
-                Decimal::ZERO
-            } else {
-                val.available.round_dp(4)
-            },
-            if val.held == Decimal::ZERO {
-                Decimal::ZERO
-            } else {
-                val.held.round_dp(4)
-            },
-            if val.total == Decimal::ZERO {
-                Decimal::ZERO
+///Trait for anything that can export the final accounts state.
+pub trait AccountExporter {
+    ///Export every account.
+    fn export(&mut self, accounts: &BTreeMap<AccountKey, AccountData>) -> Result<()>;
+}
+
+///Order in which accounts are exported. Ties are always broken by client id, then currency.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SortOrder {
+    ///Ascending client id, the default.
+    Client,
+    ///Ascending total funds.
+    Total,
+    ///Ascending available funds.
+    Available,
+    ///Unlocked accounts first, then locked ones.
+    Locked,
+}
+
+///Accounts are ordered by client id unless another order is requested.
+impl Default for SortOrder {
+    fn default() -> Self {
+        SortOrder::Client
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = ValidationError;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "client" => Ok(SortOrder::Client),
+            "total" => Ok(SortOrder::Total),
+            "available" => Ok(SortOrder::Available),
+            "locked" => Ok(SortOrder::Locked),
+            _ => Err(ValidationError::InvalidOptionValue(
+                "sort".to_owned(),
+                value.to_owned(),
+            )),
+        }
+    }
+}
+
+///Output format for exported accounts.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Format {
+    ///CSV with a header row, the default.
+    Csv,
+    ///A single JSON array of accounts.
+    Json,
+    ///One JSON object per line.
+    Ndjson,
+}
+
+///Accounts are exported as CSV unless another format is requested.
+impl Default for Format {
+    fn default() -> Self {
+        Format::Csv
+    }
+}
+
+impl FromStr for Format {
+    type Err = ValidationError;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            "ndjson" => Ok(Format::Ndjson),
+            _ => Err(ValidationError::InvalidOptionValue(
+                "format".to_owned(),
+                value.to_owned(),
+            )),
+        }
+    }
+}
+
+///Account serialized together with it's client id and currency.
+#[derive(Serialize)]
+struct AccountRow<'a> {
+    ///Client id and currency of the account, the currency is left out when empty.
+    #[serde(flatten)]
+    key: &'a AccountKey,
+    ///Account funds and locked state.
+    #[serde(flatten)]
+    account: &'a AccountData,
+    ///Unused credit of the client, only written when some account has a credit limit.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credit: Option<Decimal>,
+}
+
+///Check if any account has a credit limit, the credit column is written only then.
+fn has_credit(accounts: &BTreeMap<AccountKey, AccountData>) -> bool {
+    accounts
+        .values()
+        .any(|account| account.credit_limit > Decimal::ZERO)
+}
+
+///Return accounts in the requested order.
+pub fn sorted(
+    accounts: &BTreeMap<AccountKey, AccountData>,
+    order: SortOrder,
+) -> Vec<(&AccountKey, &AccountData)> {
+    //accounts are already ordered by client id and currency so a stable sort keeps that order for ties
+    let mut rows: Vec<(&AccountKey, &AccountData)> = accounts.iter().collect();
+    match order {
+        SortOrder::Client => {}
+        SortOrder::Total => rows.sort_by_key(|(_, val)| val.total),
+        SortOrder::Available => rows.sort_by_key(|(_, val)| val.available),
+        SortOrder::Locked => rows.sort_by_key(|(_, val)| val.locked),
+    }
+    rows
+}
+
+///Exporter that writes accounts as CSV to any writer.
+pub struct CsvExporter<W: Write> {
+    ///Underlying writer, wrap it in a `BufWriter` for large outputs.
+    writer: W,
+    ///Order of exported rows.
+    order: SortOrder,
+}
+
+impl<W: Write> CsvExporter<W> {
+    ///Create a new CSV exporter that orders rows by client id.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            order: SortOrder::default(),
+        }
+    }
+
+    ///Set the order of exported rows.
+    pub fn with_order(mut self, order: SortOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    ///Consume the exporter and return the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> AccountExporter for CsvExporter<W> {
+    fn export(&mut self, accounts: &BTreeMap<AccountKey, AccountData>) -> Result<()> {
+        //the currency column is only written when some account has a currency
+        let currencies = accounts.keys().any(|key| !key.currency.is_empty());
+
+        let credit = has_credit(accounts);
+
+        //create header
+        if currencies {
+            write!(self.writer, "client,currency,available,held,total,locked")?;
+        } else {
+            write!(self.writer, "client,available,held,total,locked")?;
+        }
+        if credit {
+            write!(self.writer, ",credit")?;
+        }
+        writeln!(self.writer)?;
+        //iterate over accounts in the requested order and output result to the writer
+        for (key, val) in sorted(accounts, self.order) {
+            if currencies {
+                write!(self.writer, "{},{},", key.client, key.currency)?;
             } else {
-                val.total.round_dp(4)
-            },
-            val.locked
-        )
+                write!(self.writer, "{},", key.client)?;
+            }
+            write!(
+                self.writer,
+                "{},{},{},{}",
+                rounded(val.available),
+                rounded(val.held),
+                rounded(val.total),
+                val.locked
+            )?;
+            if credit {
+                let remaining = remaining_credit(accounts, key.client, val.credit_limit);
+                write!(self.writer, ",{}", rounded(remaining))?;
+            }
+            writeln!(self.writer)?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+///Exporter that writes accounts as JSON to any writer, either as one array or one object per line.
+///Funds are written as strings so no precision is lost.
+pub struct JsonExporter<W: Write> {
+    ///Underlying writer, wrap it in a `BufWriter` for large outputs.
+    writer: W,
+    ///Order of exported rows.
+    order: SortOrder,
+    ///Write newline delimited objects instead of an array.
+    lines: bool,
+}
+
+impl<W: Write> JsonExporter<W> {
+    ///Create a new exporter that writes a single JSON array.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            order: SortOrder::default(),
+            lines: false,
+        }
+    }
+
+    ///Create a new exporter that writes one JSON object per line.
+    pub fn ndjson(writer: W) -> Self {
+        Self {
+            lines: true,
+            ..Self::new(writer)
+        }
+    }
+
+    ///Set the order of exported rows.
+    pub fn with_order(mut self, order: SortOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    ///Consume the exporter and return the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> AccountExporter for JsonExporter<W> {
+    fn export(&mut self, accounts: &BTreeMap<AccountKey, AccountData>) -> Result<()> {
+        let credit = has_credit(accounts);
+        let rows = sorted(accounts, self.order)
+            .into_iter()
+            .map(|(key, account)| AccountRow {
+                key,
+                account,
+                credit: Some(account.credit_limit)
+                    .filter(|_| credit)
+                    .map(|limit| rounded(remaining_credit(accounts, key.client, limit))),
+            });
+
+        if self.lines {
+            for row in rows {
+                serde_json::to_writer(&mut self.writer, &row)?;
+                writeln!(self.writer)?;
+            }
+        } else {
+            serde_json::to_writer(&mut self.writer, &rows.collect::<Vec<AccountRow>>())?;
+            writeln!(self.writer)?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+///Function that exports accounts data to stdout.
+pub fn export_to_stdout(accounts: &BTreeMap<AccountKey, AccountData>) -> Result<()> {
+    //lock stdout once instead of for every line
+    let stdout = std::io::stdout();
+    CsvExporter::new(BufWriter::new(stdout.lock())).export(accounts)
+}
+
+///Header of the audit trail CSV.
+const AUDIT_HEADER: [&str; 5] = ["client", "type", "amount", "reason", "currency"];
+
+///Header of the charged fees CSV.
+const FEE_HEADER: [&str; 5] = ["client", "tx", "type", "fee", "currency"];
+
+///Function that writes applied admin actions as CSV to any writer.
+pub fn write_audit_trail<W: Write>(writer: W, audit_trail: &[AuditEntry]) -> Result<()> {
+    let mut writer = TrailWriter::audit(writer)?;
+    for entry in audit_trail {
+        writer.write(entry)?;
+    }
+    writer.flush()
+}
+
+///Function that writes charged fees as CSV to any writer.
+pub fn write_fee_trail<W: Write>(writer: W, fees: &[FeeEntry]) -> Result<()> {
+    let mut writer = TrailWriter::fees(writer)?;
+    for entry in fees {
+        writer.write(entry)?;
+    }
+    writer.flush()
+}
+
+///Writer for the audit trail or charged fees, entries can be written as they happen.
+pub struct TrailWriter<W: Write> {
+    ///Underlying CSV writer.
+    writer: csv::Writer<W>,
+}
+
+impl<W: Write> TrailWriter<W> {
+    ///Create a new audit trail writer and write it's header.
+    pub fn audit(writer: W) -> Result<Self> {
+        Self::with_header(writer, &AUDIT_HEADER)
+    }
+
+    ///Create a new charged fees writer and write it's header.
+    pub fn fees(writer: W) -> Result<Self> {
+        Self::with_header(writer, &FEE_HEADER)
+    }
+
+    ///Create a writer that continues an output which already has it's header.
+    pub fn resume(writer: W) -> Self {
+        Self {
+            writer: csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(writer),
+        }
+    }
+
+    ///Create a new writer and write the header, it is there even when there is nothing to report.
+    fn with_header(writer: W, header: &[&str]) -> Result<Self> {
+        let mut trail_writer = Self::resume(writer);
+        trail_writer.writer.write_record(header)?;
+        Ok(trail_writer)
+    }
+
+    ///Write one entry.
+    pub fn write<T: Serialize>(&mut self, entry: &T) -> Result<()> {
+        self.writer.serialize(entry)?;
+        Ok(())
+    }
+
+    ///Flush buffered rows to the underlying writer.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    ///Underlying writer, flush first to see every written row.
+    pub fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
+}
+
+///Function that writes the house accounts collecting charged fees as CSV to any writer, one row per currency.
+pub fn write_house_accounts<W: Write>(
+    mut writer: W,
+    house_accounts: &BTreeMap<Currency, AccountData>,
+) -> Result<()> {
+    writeln!(writer, "currency,available,held,total")?;
+    for (currency, account) in house_accounts {
+        writeln!(
+            writer,
+            "{},{},{},{}",
+            currency,
+            rounded(account.available),
+            rounded(account.held),
+            rounded(account.total)
+        )?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+///Writer for records that were rejected or failed deserialization.
+///Every row keeps the original fields and gets extra `reject_line`, `reject_reason` and `reject_raw` columns,
+///they are prefixed so they do not clash with input columns such as the `reason` of an adjustment.
+pub struct DeadLetterWriter<W: Write> {
+    ///Underlying CSV writer.
+    writer: csv::Writer<W>,
+    ///Number of original fields every row is padded or cut to.
+    field_count: usize,
+}
+
+impl<W: Write> DeadLetterWriter<W> {
+    ///Create a new dead letter writer and write the header built from the input headers.
+    pub fn new(writer: W, headers: &StringRecord) -> Result<Self> {
+        let mut dead_letter = Self::resume(writer, headers);
+
+        let mut header = headers.clone();
+        header.push_field("reject_line");
+        header.push_field("reject_reason");
+        header.push_field("reject_raw");
+        dead_letter.writer.write_record(&header)?;
+
+        Ok(dead_letter)
+    }
+
+    ///Create a dead letter writer that continues an output which already has it's header.
+    pub fn resume(writer: W, headers: &StringRecord) -> Self {
+        Self {
+            writer: csv::Writer::from_writer(writer),
+            field_count: headers.len(),
+        }
+    }
+
+    ///Write one rejected record.
+    ///`raw` is the record as it was read from the input.
+    pub fn write(
+        &mut self,
+        record: &StringRecord,
+        line: u64,
+        reason: &RejectReason,
+        raw: &str,
+    ) -> Result<()> {
+        //keep the row rectangular, the raw column still holds everything that was read
+        let mut row: Vec<String> = (0..self.field_count)
+            .map(|index| record.get(index).unwrap_or("").to_owned())
+            .collect();
+        row.push(line.to_string());
+        row.push(reason.to_string());
+        row.push(raw.to_owned());
+
+        self.writer.write_record(&row)?;
+        Ok(())
+    }
+
+    ///Flush buffered rows to the underlying writer.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    ///Underlying writer, flush first to see every written row.
+    pub fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::ClientId;
+    use crate::structs::ClientIdValue;
+    use crate::structs::TransactionType;
+
+    ///Accounts of clients 1 to 3 with the given available funds, held funds and locked state.
+    fn accounts(rows: &[(Decimal, Decimal, bool)]) -> BTreeMap<AccountKey, AccountData> {
+        rows.iter()
+            .enumerate()
+            .map(|(index, (available, held, locked))| {
+                let key =
+                    AccountKey::new(ClientId(index as ClientIdValue + 1), Currency::default());
+                let data = AccountData {
+                    available: *available,
+                    held: *held,
+                    total: *available + *held,
+                    locked: *locked,
+                    ..AccountData::default()
+                };
+                (key, data)
+            })
+            .collect()
+    }
+
+    ///Client ids of the accounts in the requested order.
+    fn order(accounts: &BTreeMap<AccountKey, AccountData>, order: SortOrder) -> Vec<String> {
+        sorted(accounts, order)
+            .into_iter()
+            .map(|(key, _)| key.client.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn accounts_are_sorted_in_the_requested_order_with_ties_by_client() {
+        let accounts = accounts(&[
+            (Decimal::new(5, 0), Decimal::new(0, 0), true),
+            (Decimal::new(1, 0), Decimal::new(9, 0), false),
+            (Decimal::new(1, 0), Decimal::new(4, 0), false),
+        ]);
+
+        assert_eq!(order(&accounts, SortOrder::default()), vec!["1", "2", "3"]);
+        assert_eq!(order(&accounts, SortOrder::Total), vec!["1", "3", "2"]);
+        assert_eq!(order(&accounts, SortOrder::Available), vec!["2", "3", "1"]);
+        assert_eq!(order(&accounts, SortOrder::Locked), vec!["2", "3", "1"]);
+    }
+
+    #[test]
+    fn unknown_sort_order_is_refused() {
+        assert_eq!("total".parse(), Ok(SortOrder::Total));
+        assert_eq!(
+            "balance".parse::<SortOrder>(),
+            Err(ValidationError::InvalidOptionValue(
+                "sort".to_owned(),
+                "balance".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn json_export_writes_funds_as_rounded_strings() {
+        let accounts = accounts(&[
+            (Decimal::new(12345, 5), Decimal::new(0, 0), false),
+            (Decimal::new(2, 0), Decimal::new(1, 0), true),
+        ]);
+
+        let mut exporter = JsonExporter::new(Vec::new());
+        exporter.export(&accounts).unwrap();
+        assert_eq!(
+            String::from_utf8(exporter.into_inner()).unwrap(),
+            "[{\"client\":1,\"available\":\"0.1234\",\"held\":\"0\",\"total\":\"0.1234\",\"locked\":false},\
+             {\"client\":2,\"available\":\"2\",\"held\":\"1\",\"total\":\"3\",\"locked\":true}]\n"
+        );
+
+        let mut exporter = JsonExporter::ndjson(Vec::new()).with_order(SortOrder::Locked);
+        exporter.export(&accounts).unwrap();
+        let written = String::from_utf8(exporter.into_inner()).unwrap();
+        assert_eq!(written.lines().count(), 2);
+        assert!(written.starts_with("{\"client\":1,"));
+    }
+
+    #[test]
+    fn dead_letter_columns_do_not_clash_with_input_columns() {
+        let headers = StringRecord::from(vec!["type", "client", "tx", "amount", "reason"]);
+        let record = StringRecord::from(vec!["withdrawal", "1", "2", "50"]);
+        let mut dead_letter = DeadLetterWriter::new(Vec::new(), &headers).unwrap();
+        dead_letter
+            .write(
+                &record,
+                3,
+                &RejectReason::InsufficientFunds,
+                "withdrawal, 1, 2, 50",
+            )
+            .unwrap();
+        dead_letter.flush().unwrap();
+
+        assert_eq!(
+            String::from_utf8(dead_letter.get_ref().clone()).unwrap(),
+            "type,client,tx,amount,reason,reject_line,reject_reason,reject_raw\n\
+             withdrawal,1,2,50,,3,insufficient available funds,\"withdrawal, 1, 2, 50\"\n"
+        );
+    }
+
+    #[test]
+    fn audit_trail_has_a_header_even_when_empty() {
+        let mut output = Vec::new();
+        write_audit_trail(&mut output, &[]).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,type,amount,reason,currency\n"
+        );
+
+        let entry = AuditEntry {
+            client: ClientId(1),
+            col_type: TransactionType::Adjust,
+            amount: Some(Decimal::new(-25, 1)),
+            reason: Some("bank error".to_string()),
+            currency: Currency::default(),
+        };
+        let mut output = Vec::new();
+        write_audit_trail(&mut output, &[entry]).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,type,amount,reason,currency\n1,adjust,-2.5,bank error,\n"
+        );
+    }
+
+    #[test]
+    fn credit_column_is_written_only_when_some_account_has_a_limit() {
+        let mut accounts = accounts(&[
+            (Decimal::new(-2, 0), Decimal::new(0, 0), false),
+            (Decimal::new(15, 1), Decimal::new(0, 0), false),
+        ]);
+        let export = |accounts: &BTreeMap<AccountKey, AccountData>| {
+            let mut exporter = CsvExporter::new(Vec::new());
+            exporter.export(accounts).unwrap();
+            String::from_utf8(exporter.into_inner()).unwrap()
+        };
+
+        assert_eq!(
+            export(&accounts),
+            "client,available,held,total,locked\n1,-2,0,-2,false\n2,1.5,0,1.5,false\n"
+        );
+
+        for account in accounts.values_mut() {
+            account.credit_limit = Decimal::new(3, 0);
+        }
+        assert_eq!(
+            export(&accounts),
+            "client,available,held,total,locked,credit\n1,-2,0,-2,false,1\n2,1.5,0,1.5,false,3\n"
+        );
+
+        let mut exporter = JsonExporter::ndjson(Vec::new());
+        exporter.export(&accounts).unwrap();
+        let output = String::from_utf8(exporter.into_inner()).unwrap();
+        assert!(output.lines().next().unwrap().contains(r#""credit":"1""#));
+    }
+
+    #[test]
+    fn currency_column_is_written_only_when_some_account_has_a_currency() {
+        let mut accounts = accounts(&[(Decimal::new(1, 0), Decimal::new(0, 0), false)]);
+        let data = accounts.values().next().unwrap().clone();
+        accounts.insert(
+            AccountKey::new(ClientId(1), Currency::new("usd").unwrap()),
+            data,
+        );
+
+        let mut exporter = CsvExporter::new(Vec::new());
+        exporter.export(&accounts).unwrap();
+        assert_eq!(
+            String::from_utf8(exporter.into_inner()).unwrap(),
+            "client,currency,available,held,total,locked\n1,,1,0,1,false\n1,USD,1,0,1,false\n"
+        );
     }
 }
@@ -0,0 +1,141 @@
+//! Module for transaction fees and the fee schedule they are charged by.
+
+use crate::error::ValidationError;
+use crate::structs::ClientId;
+use crate::structs::Currency;
+use crate::structs::TransactionType;
+use crate::structs::TxId;
+use crate::validation::DEFAULT_MAX_SCALE;
+use crate::Result;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::Read;
+
+///Fees charged per transaction type. Every fee defaults to zero.
+#[derive(Debug, PartialEq, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeSchedule {
+    ///Flat fee charged on every withdrawal.
+    pub withdrawal_flat: Decimal,
+    ///Percentage of the amount charged on every withdrawal, `1.5` means 1.5%.
+    pub withdrawal_percent: Decimal,
+    ///Fixed fee charged on every chargeback.
+    pub chargeback: Decimal,
+}
+
+impl FeeSchedule {
+    ///Load a fee schedule from a JSON config, for example `{"withdrawal_flat": "0.5", "chargeback": "15"}`.
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self> {
+        let schedule: FeeSchedule = serde_json::from_reader(reader)?;
+        schedule.validate()?;
+        Ok(schedule)
+    }
+
+    ///Check that no fee is negative and fixed fees have at most 4 places past the decimal point.
+    pub fn validate(&self) -> std::result::Result<(), ValidationError> {
+        let fees = [
+            ("withdrawal_flat", self.withdrawal_flat, true),
+            ("withdrawal_percent", self.withdrawal_percent, false),
+            ("chargeback", self.chargeback, true),
+        ];
+        for (name, fee, fixed) in fees.iter() {
+            if *fee < Decimal::ZERO {
+                return Err(ValidationError::InvalidFeeSchedule(format!(
+                    "'{}' cannot be negative",
+                    name
+                )));
+            }
+            //percentages can be more precise because the charged fee is rounded
+            if *fixed && fee.normalize().scale() > DEFAULT_MAX_SCALE {
+                return Err(ValidationError::InvalidFeeSchedule(format!(
+                    "'{}' has more than {} decimal places",
+                    name, DEFAULT_MAX_SCALE
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    ///Fee charged on a withdrawal of this amount, rounded to 4 places past the decimal point.
+    ///Returns `None` when the fee is too large to be stored.
+    pub fn withdrawal_fee(&self, amount: Decimal) -> Option<Decimal> {
+        let percent = amount.checked_mul(self.withdrawal_percent / Decimal::ONE_HUNDRED)?;
+        let fee = self.withdrawal_flat.checked_add(percent)?;
+        Some(fee.round_dp(DEFAULT_MAX_SCALE))
+    }
+
+    ///Fee charged on a chargeback.
+    pub fn chargeback_fee(&self) -> Decimal {
+        self.chargeback
+    }
+}
+
+///Fee charged by the engine and credited to the house account.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct FeeEntry {
+    ///Client the fee was charged to.
+    pub client: ClientId,
+    ///Transaction that caused the fee, for chargebacks this is the referenced transaction.
+    pub tx: TxId,
+    ///Either withdrawal or chargeback.
+    #[serde(rename = "type")]
+    pub col_type: TransactionType,
+    ///Charged fee.
+    pub fee: Decimal,
+    ///Currency of the charged fee.
+    pub currency: Currency,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    fn schedule(withdrawal_flat: &str, withdrawal_percent: &str) -> FeeSchedule {
+        FeeSchedule {
+            withdrawal_flat: dec(withdrawal_flat),
+            withdrawal_percent: dec(withdrawal_percent),
+            ..FeeSchedule::default()
+        }
+    }
+
+    #[test]
+    fn withdrawal_fee_is_rounded_to_4_decimal_places() {
+        let fees = schedule("0.5", "1.5");
+        assert_eq!(fees.withdrawal_fee(dec("10")), Some(dec("0.65")));
+        //ties are rounded to the even digit
+        let fees = schedule("0", "1");
+        assert_eq!(fees.withdrawal_fee(dec("0.005")), Some(dec("0")));
+        assert_eq!(fees.withdrawal_fee(dec("0.015")), Some(dec("0.0002")));
+        assert_eq!(fees.withdrawal_fee(dec("0.0135")), Some(dec("0.0001")));
+    }
+
+    #[test]
+    fn withdrawal_fee_too_large_to_store_is_none() {
+        let fees = schedule("0", "200");
+        assert_eq!(
+            fees.withdrawal_fee(dec("60000000000000000000000000000")),
+            None
+        );
+
+        let fees = schedule("1", "100");
+        assert_eq!(fees.withdrawal_fee(Decimal::MAX), None);
+    }
+
+    #[test]
+    fn negative_or_too_precise_fees_are_refused() {
+        assert!(schedule("-1", "0").validate().is_err());
+        assert!(schedule("0.00001", "0").validate().is_err());
+        assert!(schedule("0", "0.00001").validate().is_ok());
+        assert!(FeeSchedule::from_reader(
+            r#"{"withdrawal_flat": "0.5", "chargeback": "15"}"#.as_bytes()
+        )
+        .is_ok());
+        assert!(FeeSchedule::from_reader(r#"{"deposit": "1"}"#.as_bytes()).is_err());
+    }
+}
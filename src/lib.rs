@@ -3,6 +3,17 @@
 //! compiled on stable-x86_64-unknown-linux-gnu (default)
 //! with rustc 1.53.0 (53cb7b09b 2021-06-17)
 
+pub mod checkpoint;
+pub mod credit;
+pub mod engine;
+pub mod error;
 pub mod export;
+pub mod fees;
 pub mod parser;
+pub mod snapshot;
 pub mod structs;
+pub mod validation;
+pub mod wal;
+
+pub use error::Error;
+pub use error::Result;
@@ -1,63 +1,460 @@
-use csv_parser::parser::parse_csv;
+use csv_parser::checkpoint::open_output;
+use csv_parser::checkpoint::Checkpoint;
+use csv_parser::checkpoint::DEFAULT_CHECKPOINT_INTERVAL;
+use csv_parser::credit::CreditLimits;
+use csv_parser::engine::DisputePolicy;
+use csv_parser::engine::Engine;
+use csv_parser::engine::LockPolicy;
+use csv_parser::error::ValidationError;
+use csv_parser::export::write_house_accounts;
+use csv_parser::export::AccountExporter;
+use csv_parser::export::CsvExporter;
+use csv_parser::export::Format;
+use csv_parser::export::JsonExporter;
+use csv_parser::export::SortOrder;
+use csv_parser::fees::FeeSchedule;
+use csv_parser::parser::detect_format;
+use csv_parser::parser::parse;
+use csv_parser::parser::parse_csv_resumable;
+use csv_parser::parser::read_accounts;
+use csv_parser::parser::InputFormat;
+use csv_parser::parser::Outputs;
+use csv_parser::snapshot::Snapshot;
+use csv_parser::validation::Validator;
+use csv_parser::wal::EventLog;
+use csv_parser::Result;
+use rust_decimal::Decimal;
 use std::env;
-use std::io::ErrorKind;
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
 use std::path::Path;
+use std::process;
+use std::slice::Iter;
+use std::str::FromStr;
 
-///main function, can throw io error.
-fn main() -> Result<(), std::io::Error> {
-    //get a list of arguments passed to our program
-    let arguments: Vec<String> = env::args().collect();
+///Options collected from the command line.
+#[derive(Debug, Default)]
+struct Options {
+    ///Path of the file to parse, stdin is used when missing or '-'.
+    input: Option<String>,
+    ///Format of the input, detected from the extension or content when missing.
+    input_format: Option<InputFormat>,
+    ///Path of the CSV file that receives rejected records.
+    dead_letter: Option<String>,
+    ///Print the line and reason of every rejected record on stderr.
+    report_rejections: bool,
+    ///Path of the file that receives accounts, stdout is used when missing.
+    output: Option<String>,
+    ///Path of the CSV file that receives applied admin actions.
+    audit: Option<String>,
+    ///Path of the snapshot the engine state is restored from.
+    restore: Option<String>,
+    ///Path of the accounts CSV with opening balances.
+    opening_balances: Option<String>,
+    ///Path of the event log applied records are appended to.
+    event_log: Option<String>,
+    ///Number of events written between two syncs of the event log.
+    event_log_batch: Option<usize>,
+    ///Path of the event log the engine state is rebuilt from before processing.
+    replay: Option<String>,
+    ///Path of the checkpoint processing is resumed from and saved to.
+    checkpoint: Option<String>,
+    ///Number of records processed between two checkpoints.
+    checkpoint_every: Option<u64>,
+    ///Path of the file that receives a snapshot of the final engine state.
+    snapshot: Option<String>,
+    ///Path of the JSON fee schedule config.
+    fees: Option<String>,
+    ///Path of the CSV file that receives charged fees.
+    fee_log: Option<String>,
+    ///Path of the CSV file that receives the house account balances.
+    house_accounts: Option<String>,
+    ///Order of exported accounts.
+    sort: SortOrder,
+    ///Format of exported accounts.
+    format: Format,
+    ///How disputes over withdrawals are handled.
+    dispute_policy: DisputePolicy,
+    ///What a locked account still accepts.
+    lock_policy: LockPolicy,
+    ///Maximum places past the decimal point of an amount.
+    max_scale: Option<u32>,
+    ///Maximum amount of a single transaction.
+    max_amount: Option<Decimal>,
+    ///Credit limit of clients without an override.
+    credit_limit: Option<Decimal>,
+    ///Path of the `client,limit` CSV file with credit limits of single clients.
+    credit_limits: Option<String>,
+}
+
+///Parse a numeric option value.
+fn parse_value<T: FromStr>(option: &str, value: &str) -> Result<T> {
+    value.parse().map_err(|_| {
+        ValidationError::InvalidOptionValue(
+            option.trim_start_matches('-').to_owned(),
+            value.to_owned(),
+        )
+        .into()
+    })
+}
+
+///Get the value that follows an option.
+fn option_value<'a>(iter: &mut Iter<'a, String>, argument: &str) -> Result<&'a String> {
+    iter.next()
+        .ok_or_else(|| ValidationError::MissingOptionValue(argument.to_owned()).into())
+}
 
-    //println!("{:#?}", arguments);
+///Collect options from command line arguments (without the path of the binary).
+fn parse_arguments(arguments: &[String]) -> Result<Options> {
+    let mut options = Options::default();
+    let mut iter = arguments.iter();
 
-    //check if we actualy got some args or nothing was provided
-    //we always get a first argument (this is the path of the binary)
-    if arguments.len() == 1 {
-        return Err(std::io::Error::new(
-            ErrorKind::InvalidInput,
-            "Cannot continue, please provide a file name for parsing.",
-        ));
+    while let Some(argument) = iter.next() {
+        match argument.as_str() {
+            "--dead-letter" => {
+                let value = option_value(&mut iter, argument)?;
+                options.dead_letter = Some(value.to_owned());
+            }
+            "--report-rejections" => {
+                options.report_rejections = true;
+            }
+            "--output" => {
+                let value = option_value(&mut iter, argument)?;
+                options.output = Some(value.to_owned());
+            }
+            "--input-format" => {
+                let value = option_value(&mut iter, argument)?;
+                options.input_format = Some(value.parse()?);
+            }
+            "--withdrawal-disputes" => {
+                let value = option_value(&mut iter, argument)?;
+                options.dispute_policy = value.parse()?;
+            }
+            "--lock-policy" => {
+                let value = option_value(&mut iter, argument)?;
+                options.lock_policy = value.parse()?;
+            }
+            "--max-scale" => {
+                let value = option_value(&mut iter, argument)?;
+                options.max_scale = Some(parse_value(argument, value)?);
+            }
+            "--max-amount" => {
+                let value = option_value(&mut iter, argument)?;
+                options.max_amount = Some(parse_value(argument, value)?);
+            }
+            "--credit-limit" => {
+                let value = option_value(&mut iter, argument)?;
+                options.credit_limit = Some(parse_value(argument, value)?);
+            }
+            "--credit-limits" => {
+                let value = option_value(&mut iter, argument)?;
+                options.credit_limits = Some(value.to_owned());
+            }
+            "--format" => {
+                let value = option_value(&mut iter, argument)?;
+                options.format = value.parse()?;
+            }
+            "--audit" => {
+                let value = option_value(&mut iter, argument)?;
+                options.audit = Some(value.to_owned());
+            }
+            "--restore" => {
+                let value = option_value(&mut iter, argument)?;
+                options.restore = Some(value.to_owned());
+            }
+            "--opening-balances" => {
+                let value = option_value(&mut iter, argument)?;
+                options.opening_balances = Some(value.to_owned());
+            }
+            "--event-log" => {
+                let value = option_value(&mut iter, argument)?;
+                options.event_log = Some(value.to_owned());
+            }
+            "--event-log-batch" => {
+                let value = option_value(&mut iter, argument)?;
+                options.event_log_batch = Some(parse_value(argument, value)?);
+            }
+            "--replay" => {
+                let value = option_value(&mut iter, argument)?;
+                options.replay = Some(value.to_owned());
+            }
+            "--checkpoint" => {
+                let value = option_value(&mut iter, argument)?;
+                options.checkpoint = Some(value.to_owned());
+            }
+            "--checkpoint-every" => {
+                let value = option_value(&mut iter, argument)?;
+                options.checkpoint_every = Some(parse_value(argument, value)?);
+            }
+            "--snapshot" => {
+                let value = option_value(&mut iter, argument)?;
+                options.snapshot = Some(value.to_owned());
+            }
+            "--fees" => {
+                let value = option_value(&mut iter, argument)?;
+                options.fees = Some(value.to_owned());
+            }
+            "--fee-log" => {
+                let value = option_value(&mut iter, argument)?;
+                options.fee_log = Some(value.to_owned());
+            }
+            "--house-accounts" => {
+                let value = option_value(&mut iter, argument)?;
+                options.house_accounts = Some(value.to_owned());
+            }
+            "--sort" => {
+                let value = option_value(&mut iter, argument)?;
+                options.sort = value.parse()?;
+            }
+            _ if argument.starts_with("--") => {
+                return Err(ValidationError::UnknownOption(argument.to_owned()).into());
+            }
+            //first positional argument is the input file, we ignore any positional arguments passed after this one
+            _ => {
+                if options.input.is_none() {
+                    options.input = Some(argument.to_owned());
+                }
+            }
+        }
     }
 
-    //get first argument (second one from arguments vector) and check it's validity (we ignore any arguments passed after this one)
-    let first_arg = &arguments[1];
+    Ok(options)
+}
 
-    //check to see if it has a csv extension format (before checking if the file is on disk)
-    if !first_arg.ends_with(".csv") {
-        return Err(std::io::Error::new(
-            ErrorKind::Other,
-            "Cannot continue, file extension must end with '.csv'.",
-        ));
+///Get the input format from a file extension.
+///Returns `None` for '.json' because both JSON and NDJSON use it, the content decides then.
+fn extension_format(first_arg: &str) -> Result<Option<InputFormat>> {
+    if first_arg.ends_with(".csv") {
+        Ok(Some(InputFormat::Csv))
+    } else if first_arg.ends_with(".ndjson") || first_arg.ends_with(".jsonl") {
+        Ok(Some(InputFormat::Ndjson))
+    } else if first_arg.ends_with(".json") {
+        Ok(None)
+    } else {
+        Err(ValidationError::InvalidExtension(first_arg.to_owned()).into())
     }
-    //check if file is on disk an readable, dont open it yet, maybe we can stream it later
+}
+
+///Check the input file and open it for reading.
+fn open_input(first_arg: &str) -> Result<File> {
+    //check if file is on disk an readable before opening it
     let file_path = Path::new(first_arg);
     if !file_path.exists() {
-        return Err(std::io::Error::new(
-            ErrorKind::Other,
-            format!("Cannot continue, file '{}' does not exist.", first_arg),
-        ));
+        return Err(ValidationError::FileNotFound(first_arg.to_owned()).into());
     }
 
     match file_path.metadata() {
         Ok(meta) => {
             if !meta.is_file() {
-                return Err(std::io::Error::new(
-                    ErrorKind::Other,
-                    format!("Cannot continue, '{}' is not a file.", first_arg),
-                ));
+                return Err(ValidationError::NotAFile(first_arg.to_owned()).into());
             }
         }
         Err(_) => {
-            return Err(std::io::Error::new(
-                ErrorKind::Other,
-                format!(
-                    "Cannot continue, cannot access file '{}' metadata.",
-                    first_arg
-                ),
-            ));
+            return Err(ValidationError::Metadata(first_arg.to_owned()).into());
+        }
+    }
+
+    //open the file or return error
+    Ok(File::open(file_path)?)
+}
+
+///main function, prints the error message and exits with a non zero code when the run fails.
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+///Run the program, can throw crate error.
+fn run() -> Result<()> {
+    //get a list of arguments passed to our program
+    //we always get a first argument (this is the path of the binary) so skip it
+    let arguments: Vec<String> = env::args().skip(1).collect();
+    let options = parse_arguments(&arguments)?;
+
+    //read from stdin if no input file was provided or it is '-'
+    let path = options.input.as_deref().filter(|path| *path != "-");
+
+    //explicit format wins, then the file extension (checked before the file is opened), then the content
+    let mut input_format = options.input_format;
+    if let (None, Some(path)) = (input_format, path) {
+        input_format = extension_format(path)?;
+    }
+
+    //resuming needs to seek in the input so it only works for CSV files
+    if options.checkpoint.is_some() && (path.is_none() || input_format != Some(InputFormat::Csv)) {
+        return Err(ValidationError::CheckpointUnsupported.into());
+    }
+
+    //replayed events are applied on top of the restored state, so events of the run that wrote the snapshot
+    //would be applied twice
+    if options.restore.is_some() && options.replay.is_some() {
+        return Err(ValidationError::ReplayWithRestore.into());
+    }
+
+    //a saved checkpoint means an interrupted run is resumed, it's outputs are cut back to the checkpoint
+    let saved = match &options.checkpoint {
+        Some(checkpoint) => Checkpoint::load(checkpoint)?,
+        None => None,
+    };
+
+    //check amounts before they reach the engine
+    let mut validator = Validator::new();
+    if let Some(max_scale) = options.max_scale {
+        validator = validator.with_max_scale(max_scale);
+    }
+    if let Some(max_amount) = options.max_amount {
+        validator = validator.with_max_amount(max_amount);
+    }
+
+    //load the fee schedule if one was provided, no fees are charged otherwise
+    let fee_schedule = match &options.fees {
+        Some(path) => FeeSchedule::from_reader(BufReader::new(open_input(path)?))?,
+        None => FeeSchedule::default(),
+    };
+
+    //clients cannot overdraw unless a credit limit is provided
+    let mut credit_limits = CreditLimits::new();
+    if let Some(credit_limit) = options.credit_limit {
+        credit_limits = credit_limits.with_default(credit_limit)?;
+    }
+    if let Some(path) = &options.credit_limits {
+        credit_limits = credit_limits.with_overrides(BufReader::new(open_input(path)?))?;
+    }
+
+    let mut engine = Engine::new()
+        .with_dispute_policy(options.dispute_policy)
+        .with_lock_policy(options.lock_policy)
+        .with_fee_schedule(fee_schedule)
+        .with_credit_limits(credit_limits)
+        .with_validator(validator)
+        .with_event_tracking(options.event_log.is_some());
+    //continue from the state of a previous run if requested
+    if let Some(path) = &options.restore {
+        engine = engine.with_snapshot(Snapshot::read(BufReader::new(open_input(path)?))?);
+    }
+    //start from opening balances if requested, these win over restored accounts
+    if let Some(path) = &options.opening_balances {
+        engine = engine.with_accounts(read_accounts(open_input(path)?)?);
+    }
+    //rebuild the state a crashed run left in it's event log, records it already applied are skipped below
+    if let Some(path) = &options.replay {
+        engine.replay(BufReader::new(open_input(path)?))?;
+    }
+
+    //a resumed run cuts it's outputs back to the checkpoint, a run recovered by replay cuts them back to the last
+    //replayed record, the rows of records it processes again are dropped
+    let lengths = match &saved {
+        Some(saved) => saved.outputs.clone(),
+        None => engine.replayed_outputs().clone(),
+    };
+
+    //open the dead letter, audit and fee files if requested, they are written while records are processed
+    let mut dead_letter = match &options.dead_letter {
+        Some(path) => Some(BufWriter::new(open_output(path, lengths.dead_letter)?)),
+        None => None,
+    };
+    let mut audit = match &options.audit {
+        Some(path) => Some(BufWriter::new(open_output(path, lengths.audit)?)),
+        None => None,
+    };
+    let mut fee_log = match &options.fee_log {
+        Some(path) => Some(BufWriter::new(open_output(path, lengths.fee_log)?)),
+        None => None,
+    };
+
+    //open the event log if one was requested, new events are appended to existing ones
+    //and events logged after the checkpoint of a resumed run are dropped, they are logged again
+    let mut event_log = match &options.event_log {
+        Some(path) => {
+            let mut event_log = match lengths.event_log {
+                Some(length) => EventLog::open_truncated(path, length)?,
+                None => EventLog::open(path)?,
+            };
+            if let Some(batch_size) = options.event_log_batch {
+                event_log = event_log.with_batch_size(batch_size);
+            }
+            Some(event_log)
+        }
+        None => None,
+    };
+
+    //rejections are written as they happen so they are not kept in memory, the dead letter file records them as well
+    let mut rejections: Box<dyn Write> = if options.report_rejections {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::sink())
+    };
+    let outputs = Outputs {
+        rejections: Some(&mut rejections),
+        dead_letter: dead_letter.as_mut().map(|writer| writer as &mut dyn Write),
+        audit: audit.as_mut().map(|writer| writer as &mut dyn Write),
+        fee_log: fee_log.as_mut().map(|writer| writer as &mut dyn Write),
+        event_log: event_log.as_mut(),
+        lengths,
+    };
+    match (&options.checkpoint, path) {
+        //continue from the checkpoint if it exists, a saved checkpoint replaces any restored state
+        (Some(checkpoint), Some(path)) => parse_csv_resumable(
+            open_input(path)?,
+            &mut engine,
+            outputs,
+            Path::new(checkpoint),
+            saved,
+            options
+                .checkpoint_every
+                .unwrap_or(DEFAULT_CHECKPOINT_INTERVAL),
+        )?,
+        _ => {
+            let mut input: Box<dyn BufRead> = match path {
+                None => Box::new(BufReader::new(io::stdin())),
+                Some(path) => Box::new(BufReader::new(open_input(path)?)),
+            };
+
+            let input_format = match input_format {
+                Some(format) => format,
+                None => detect_format(&mut input)?,
+            };
+
+            parse(input, input_format, &mut engine, outputs)?
+        }
+    };
+
+    //write the final engine state so the next run can continue from it
+    //the previous snapshot is only replaced once the new one is complete, it can be the one restored from
+    if let Some(path) = &options.snapshot {
+        engine.snapshot().save(Path::new(path))?;
+    }
+
+    //write the collected fees per currency if requested
+    if let Some(path) = &options.house_accounts {
+        write_house_accounts(BufWriter::new(File::create(path)?), engine.house_accounts())?;
+    }
+
+    //export accounts to the output file or stdout
+    let output: Box<dyn Write> = match &options.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    let output = BufWriter::new(output);
+    let mut exporter: Box<dyn AccountExporter> = match options.format {
+        Format::Csv => Box::new(CsvExporter::new(output).with_order(options.sort)),
+        Format::Json => Box::new(JsonExporter::new(output).with_order(options.sort)),
+        Format::Ndjson => Box::new(JsonExporter::ndjson(output).with_order(options.sort)),
+    };
+    exporter.export(engine.accounts())?;
+
+    //a finished run does not need to be resumed, the next input starts from the beginning
+    if let Some(checkpoint) = &options.checkpoint {
+        if Path::new(checkpoint).exists() {
+            std::fs::remove_file(checkpoint)?;
         }
     }
 
-    //call parse_csv function from module and return result in main
-    return parse_csv(first_arg);
+    Ok(())
 }
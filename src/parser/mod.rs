@@ -1,27 +1,499 @@
-//! Module for parsing CSV and transaction engine.
+//! Module for parsing CSV and JSON input and feeding records to the transaction engine.
 
-use crate::export::export_to_stdout;
+use crate::checkpoint::Checkpoint;
+use crate::checkpoint::InputHash;
+use crate::checkpoint::OutputLengths;
+use crate::engine::AuditEntry;
+use crate::engine::Engine;
+use crate::engine::Outcome;
+use crate::engine::RejectReason;
+use crate::error::ValidationError;
+use crate::export::DeadLetterWriter;
+use crate::export::TrailWriter;
+use crate::fees::FeeEntry;
 use crate::structs::AccountData;
-use crate::structs::Transaction;
-use crate::structs::TransactionType;
+use crate::structs::AccountKey;
+use crate::structs::AccountRecord;
+use crate::structs::ClientId;
+use crate::structs::Currency;
+use crate::structs::TransactionRecord;
+use crate::wal::EventLog;
+use crate::Result;
+use csv::ByteRecord;
+use csv::Position;
+use csv::Reader;
 use csv::ReaderBuilder;
+use csv::StringRecord;
 use csv::Trim;
-use rust_decimal::Decimal;
-use std::collections::HashMap;
-use std::fs::File;
+use serde_json::Value;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::io::BufRead;
 use std::io::BufReader;
-use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::rc::Rc;
+use std::str::FromStr;
 
-///Function for parsing CSV data and handling transactions.
-pub fn parse_csv(path: &String) -> Result<(), std::io::Error> {
-    //create a mutable hashmap for storing client data
-    let mut accounts_map: HashMap<u16, AccountData> = HashMap::new();
+///Field names of a transaction, used as dead letter headers for JSON input.
+const TRANSACTION_FIELDS: [&str; 7] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "to_client",
+    "currency",
+    "reason",
+];
 
-    //this is a little messy because we are storing transaction id in two places
-    //but i'm really time limited, maybe i will come back to this
-    //anyway this provides a quick lookup by transaction id
+///Format of transaction input.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum InputFormat {
+    ///CSV with a header row.
+    Csv,
+    ///A single JSON array of transactions, loaded fully in memory.
+    Json,
+    ///One JSON transaction per line, streamed like CSV rows.
+    Ndjson,
+}
+
+impl FromStr for InputFormat {
+    type Err = ValidationError;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "csv" => Ok(InputFormat::Csv),
+            "json" => Ok(InputFormat::Json),
+            "ndjson" => Ok(InputFormat::Ndjson),
+            _ => Err(ValidationError::InvalidOptionValue(
+                "input-format".to_owned(),
+                value.to_owned(),
+            )),
+        }
+    }
+}
+
+///Detect the input format from the first non whitespace byte without consuming it.
+///An array means JSON, an object means NDJSON, anything else is treated as CSV.
+pub fn detect_format<R: BufRead>(input: &mut R) -> Result<InputFormat> {
+    loop {
+        let buffer = input.fill_buf()?;
+        //empty input is parsed as CSV so it reports a missing header the usual way
+        let byte = match buffer.first() {
+            Some(byte) => *byte,
+            None => return Ok(InputFormat::Csv),
+        };
+
+        if byte.is_ascii_whitespace() {
+            input.consume(1);
+            continue;
+        }
+
+        return Ok(match byte {
+            b'[' => InputFormat::Json,
+            b'{' => InputFormat::Ndjson,
+            _ => InputFormat::Csv,
+        });
+    }
+}
+
+///Rejected record together with the line it was read from.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Rejection {
+    ///Line number of the record inside the input, for JSON arrays this is the element number.
+    pub line: u64,
+    ///Reason why the record did not change any account.
+    pub reason: RejectReason,
+}
+
+///Result of parsing an input.
+#[derive(Debug)]
+pub struct Report {
+    ///Every record that was rejected, empty when rejections were written to an output instead.
+    pub rejections: Vec<Rejection>,
+    ///Applied admin actions in the order they were processed.
+    pub audit_trail: Vec<AuditEntry>,
+    ///Charged fees in the order they were processed.
+    pub fees: Vec<FeeEntry>,
+}
+
+///Optional outputs written while records are processed.
+///When a rejection, audit or fee writer is provided the entries are written as they happen instead of being returned
+///in the report, so they are not kept in memory and a run resumed from a checkpoint keeps the ones written before it.
+#[derive(Default)]
+pub struct Outputs<'a> {
+    ///Receives the line and reason of every rejected record, one per line.
+    pub rejections: Option<&'a mut dyn Write>,
+    ///Receives rejected records as CSV.
+    pub dead_letter: Option<&'a mut dyn Write>,
+    ///Receives applied admin actions as CSV.
+    pub audit: Option<&'a mut dyn Write>,
+    ///Receives charged fees as CSV.
+    pub fee_log: Option<&'a mut dyn Write>,
+    ///Receives events of applied records.
+    pub event_log: Option<&'a mut EventLog>,
+    ///Lengths the outputs already have, headers are only written to empty outputs.
+    ///Set these when outputs of an earlier run are continued, see `Engine::replayed_outputs`.
+    pub lengths: OutputLengths,
+}
+
+///Writer that keeps the length of the output it writes to, starting from the length the output already had.
+struct CountingWriter<W> {
+    ///Underlying writer.
+    inner: W,
+    ///Length of the output including everything written so far.
+    length: u64,
+    ///Set while flushes should reach the underlying writer, shared by the writers of one collector.
+    flush_inner: Rc<Cell<bool>>,
+}
+
+impl<W> CountingWriter<W> {
+    ///Create a new writer for an output that already has the given length.
+    fn new(inner: W, length: u64, flush_inner: &Rc<Cell<bool>>) -> Self {
+        Self {
+            inner,
+            length,
+            flush_inner: Rc::clone(flush_inner),
+        }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.length += count as u64;
+        Ok(count)
+    }
+
+    //otherwise only the rows buffered by the csv writer are counted,
+    //so taking the lengths after every record does not flush the output each time
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.flush_inner.get() {
+            self.inner.flush()?;
+        }
+        Ok(())
+    }
+}
+
+///Output writer that counts the bytes it writes.
+type Counted<'a> = CountingWriter<&'a mut dyn Write>;
+
+///Feeds records to the engine and keeps track of rejected ones.
+struct Collector<'e, 'a> {
+    ///Engine that holds client data and transactions.
+    engine: &'e mut Engine,
+    ///Rows that did not change any account, only kept when they are not written.
+    rejections: Vec<Rejection>,
+    ///Optional writer for the line and reason of rejected rows.
+    rejection_log: Option<&'a mut dyn Write>,
+    ///Optional writer for rejected rows.
+    dead_letter: Option<DeadLetterWriter<Counted<'a>>>,
+    ///Optional writer for applied admin actions.
+    audit: Option<TrailWriter<Counted<'a>>>,
+    ///Optional writer for charged fees.
+    fee_log: Option<TrailWriter<Counted<'a>>>,
+    ///Optional log for events of applied records.
+    event_log: Option<&'a mut EventLog>,
+    ///Last input line that was read, replay needs the input to reach the replayed line.
+    line: u64,
+    ///Set while the counted outputs are flushed to the underlying writers.
+    flush_inner: Rc<Cell<bool>>,
+}
+
+impl<'e, 'a> Collector<'e, 'a> {
+    ///Create a new collector, the dead letter header is built from `headers`.
+    ///Outputs continue at their given lengths, headers are only written to empty outputs.
+    fn new(engine: &'e mut Engine, headers: &StringRecord, outputs: Outputs<'a>) -> Result<Self> {
+        let lengths = &outputs.lengths;
+        let flush_inner = Rc::new(Cell::new(false));
+        let dead_letter = match (outputs.dead_letter, lengths.dead_letter.unwrap_or(0)) {
+            (Some(writer), 0) => Some(DeadLetterWriter::new(
+                CountingWriter::new(writer, 0, &flush_inner),
+                headers,
+            )?),
+            (Some(writer), length) => Some(DeadLetterWriter::resume(
+                CountingWriter::new(writer, length, &flush_inner),
+                headers,
+            )),
+            (None, _) => None,
+        };
+        let audit = trail_writer(
+            outputs.audit,
+            lengths.audit,
+            &flush_inner,
+            TrailWriter::audit,
+        )?;
+        let fee_log = trail_writer(
+            outputs.fee_log,
+            lengths.fee_log,
+            &flush_inner,
+            TrailWriter::fees,
+        )?;
+
+        Ok(Self {
+            engine,
+            rejections: Vec::new(),
+            rejection_log: outputs.rejections,
+            dead_letter,
+            audit,
+            fee_log,
+            event_log: outputs.event_log,
+            line: 0,
+            flush_inner,
+        })
+    }
+
+    ///Record the outcome of one input record, the input up to the end of the record has the given hash.
+    fn push(
+        &mut self,
+        line: u64,
+        input_hash: InputHash,
+        outcome: Outcome,
+        record: &StringRecord,
+        raw: &str,
+    ) -> Result<()> {
+        //trails are only taken when they are written, otherwise they are returned in the report
+        if let Some(writer) = self.audit.as_mut() {
+            for entry in self.engine.take_audit_trail() {
+                writer.write(&entry)?;
+            }
+        }
+        if let Some(writer) = self.fee_log.as_mut() {
+            for entry in self.engine.take_fee_trail() {
+                writer.write(&entry)?;
+            }
+        }
+
+        if let Outcome::Rejected(reason) = outcome {
+            if let Some(writer) = self.dead_letter.as_mut() {
+                writer.write(record, line, &reason, raw)?;
+            }
+            match self.rejection_log.as_mut() {
+                Some(writer) => writeln!(writer, "line {}: {}", line, reason)?,
+                None => self.rejections.push(Rejection { line, reason }),
+            }
+        }
+
+        //events are always taken so they do not pile up in the engine
+        //they are logged after the rows of the record, so the output lengths they carry include them
+        let outputs = Some(self.lengths()?).filter(|lengths| *lengths != OutputLengths::default());
+        for mut event in self.engine.take_events() {
+            let syncs = match self.event_log.as_ref() {
+                Some(event_log) => event_log.syncs_next(),
+                None => continue,
+            };
+            //rows of every logged record are on disk before the log is, so a recovered run can cut them back
+            if syncs {
+                self.flush_outputs()?;
+            }
+            if let Some(event_log) = self.event_log.as_mut() {
+                event.line = line;
+                event.input_hash = input_hash.value();
+                event.outputs = outputs.clone();
+                event_log.append(&event)?;
+            }
+        }
+        Ok(())
+    }
+
+    ///Lengths of the outputs including every written row, the event log is left out.
+    fn lengths(&mut self) -> Result<OutputLengths> {
+        self.flush_counted()?;
+        Ok(OutputLengths {
+            dead_letter: self
+                .dead_letter
+                .as_ref()
+                .map(|writer| writer.get_ref().length),
+            audit: self.audit.as_ref().map(|writer| writer.get_ref().length),
+            fee_log: self.fee_log.as_ref().map(|writer| writer.get_ref().length),
+            event_log: None,
+        })
+    }
+
+    ///Check if the record at an input line was already applied by a replayed event log.
+    ///The input up to the replayed line must hash the same as when the log was written, otherwise records
+    ///of another input would be skipped.
+    fn replayed(&mut self, line: u64, input_hash: InputHash) -> Result<bool> {
+        self.line = self.line.max(line);
+        let replayed_line = self.engine.replayed_line();
+        if line == replayed_line && input_hash != self.engine.replayed_hash() {
+            return Err(ValidationError::ReplayMismatch(replayed_line).into());
+        }
+        Ok(line <= replayed_line)
+    }
+
+    ///Flush every output besides the event log.
+    fn flush_outputs(&mut self) -> Result<()> {
+        if let Some(writer) = self.rejection_log.as_mut() {
+            writer.flush()?;
+        }
+        self.flush_inner.set(true);
+        let flushed = self.flush_counted();
+        self.flush_inner.set(false);
+        flushed
+    }
+
+    ///Flush the rows buffered by the csv writers of the counted outputs.
+    fn flush_counted(&mut self) -> Result<()> {
+        if let Some(writer) = self.dead_letter.as_mut() {
+            writer.flush()?;
+        }
+        if let Some(writer) = self.audit.as_mut() {
+            writer.flush()?;
+        }
+        if let Some(writer) = self.fee_log.as_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    ///Flush every output and sync the event log, returns the lengths of the outputs.
+    fn flush(&mut self) -> Result<OutputLengths> {
+        self.flush_outputs()?;
+        let mut lengths = self.lengths()?;
+        if let Some(event_log) = self.event_log.as_mut() {
+            event_log.sync()?;
+            lengths.event_log = Some(event_log.synced_len()?);
+        }
+        Ok(lengths)
+    }
+
+    ///Flush outputs and save the engine state together with the position of the next record.
+    fn save_checkpoint(
+        &mut self,
+        path: &Path,
+        position: &Position,
+        input_hash: InputHash,
+    ) -> Result<()> {
+        //everything before the checkpoint must be durable before the checkpoint is
+        let lengths = self.flush()?;
+
+        Checkpoint::new(position, input_hash, self.engine.snapshot())
+            .with_outputs(lengths)
+            .save(path)
+    }
+
+    ///Flush every output, sync the event log and return the report.
+    ///An input that ends before the replayed line is not the input the replayed log was written for.
+    fn finish(mut self) -> Result<Report> {
+        self.flush()?;
+        if self.line < self.engine.replayed_line() {
+            return Err(ValidationError::ReplayMismatch(self.engine.replayed_line()).into());
+        }
+
+        Ok(Report {
+            audit_trail: self.engine.take_audit_trail(),
+            fees: self.engine.take_fee_trail(),
+            rejections: self.rejections,
+        })
+    }
+}
+
+///Create a trail writer when an output is provided, the header is only written to an empty output.
+fn trail_writer<'a>(
+    writer: Option<&'a mut dyn Write>,
+    length: Option<u64>,
+    flush_inner: &Rc<Cell<bool>>,
+    new: fn(Counted<'a>) -> Result<TrailWriter<Counted<'a>>>,
+) -> Result<Option<TrailWriter<Counted<'a>>>> {
+    Ok(match (writer, length.unwrap_or(0)) {
+        (Some(writer), 0) => Some(new(CountingWriter::new(writer, 0, flush_inner))?),
+        (Some(writer), length) => Some(TrailWriter::resume(CountingWriter::new(
+            writer,
+            length,
+            flush_inner,
+        ))),
+        (None, _) => None,
+    })
+}
+
+///Function for parsing data in the given format and handling transactions with `engine`. The final account state stays in `engine`.
+///Rejected records, applied admin actions, charged fees and events of applied records are also written to
+///the provided outputs.
+pub fn parse<R: Read>(
+    input: R,
+    format: InputFormat,
+    engine: &mut Engine,
+    outputs: Outputs,
+) -> Result<Report> {
+    match format {
+        InputFormat::Csv => parse_csv(input, engine, outputs),
+        InputFormat::Json => parse_json(input, engine, outputs),
+        InputFormat::Ndjson => parse_ndjson(input, engine, outputs),
+    }
+}
+
+///Function for parsing CSV data from any reader and handling transactions with `engine`. The final account state stays in `engine`.
+///Rejected records are also written to the dead letter output as CSV when it is provided.
+pub fn parse_csv<R: Read>(input: R, engine: &mut Engine, outputs: Outputs) -> Result<Report> {
+    let mut csv_reader = csv_reader(RecordingReader::new(input));
+
+    //headers are needed for deserializing every record to Transaction struct
+    let headers = csv_reader.headers()?.clone();
+    let mut collector = Collector::new(engine, &headers, outputs)?;
+    process_csv(&mut csv_reader, &mut collector, None)?;
+
+    //at this point parsing and transactions engine should be finished
+    //exporting is left to the caller
+    collector.finish()
+}
+
+///Function for parsing CSV data that can be resumed after an interruption.
+///Every `interval` records, or less often once the engine holds more than ten accounts and transactions for each of them,
+///a checkpoint with the input position, an engine snapshot and the output lengths
+///is saved to `checkpoint`. When a saved checkpoint is provided the engine is restored from it and parsing
+///continues at the saved position. The outputs must already be cut back to the saved lengths,
+///see `checkpoint::open_output` and `EventLog::open_truncated`.
+pub fn parse_csv_resumable<R: Read + Seek>(
+    input: R,
+    engine: &mut Engine,
+    mut outputs: Outputs,
+    checkpoint: &Path,
+    saved: Option<Checkpoint>,
+    interval: u64,
+) -> Result<Report> {
+    let mut input = input;
+
+    //a checkpoint saved for another input would skip records that were never processed
+    if let Some(saved) = &saved {
+        let input_hash = InputHash::of_prefix(&mut input, saved.byte)?;
+        if input_hash.value() != saved.input_hash {
+            return Err(
+                ValidationError::CheckpointMismatch(checkpoint.display().to_string()).into(),
+            );
+        }
+        input.seek(SeekFrom::Start(0))?;
+    }
+
+    let mut csv_reader = csv_reader(RecordingReader::new(input));
+    let headers = csv_reader.headers()?.clone();
+
+    //continue where the previous run saved it's last checkpoint
+    if let Some(saved) = saved {
+        let position = saved.position();
+        outputs.lengths = saved.outputs;
+        *engine = std::mem::take(engine).with_snapshot(saved.snapshot);
+        csv_reader.seek(position)?;
+        csv_reader
+            .get_mut()
+            .resume(InputHash::from_value(saved.input_hash));
+    }
 
-    let mut transactions_map: HashMap<u32, Transaction> = HashMap::new();
+    let mut collector = Collector::new(engine, &headers, outputs)?;
+    process_csv(
+        &mut csv_reader,
+        &mut collector,
+        Some((checkpoint, interval.max(1))),
+    )?;
+
+    collector.finish()
+}
+
+///Create a CSV reader with the settings every transaction input uses.
+fn csv_reader<R: Read>(input: R) -> Reader<R> {
     //create a csv reader builder
     let mut uninit_builder = ReaderBuilder::new();
     //set that csv will always have headers
@@ -31,257 +503,848 @@ pub fn parse_csv(path: &String) -> Result<(), std::io::Error> {
     //set flexible mode on, maybe fields such as amount are ommited
     uninit_builder.flexible(true);
 
-    //open the file or return error
-    let csv_file = match File::open(path) {
-        Ok(file) => file,
-        Err(_) => {
-            return Err(std::io::Error::new(
-                ErrorKind::Other,
-                format!("Cannot continue, unable to read file '{}'.", path),
-            ));
+    //read to csv using the reader, csv reader does it's own buffering
+    uninit_builder.from_reader(input)
+}
+
+///Number of accounts and transactions a checkpoint can hold for every record processed since the last one.
+const STATE_PER_RECORD: u64 = 10;
+
+///Feed every remaining CSV record to the collector.
+///A checkpoint is saved to the given path every given number of records when one is provided,
+///or less often when the engine holds more than ten accounts and transactions for each of them.
+fn process_csv<R: Read>(
+    csv_reader: &mut Reader<RecordingReader<R>>,
+    collector: &mut Collector,
+    checkpoint: Option<(&Path, u64)>,
+) -> Result<()> {
+    //byte records are read so invalid UTF-8 still reaches the dead letter output
+    let headers = csv_reader.byte_headers()?.clone();
+    let mut record = ByteRecord::new();
+    let mut count: u64 = 0;
+
+    //iterate over records
+    loop {
+        let start = csv_reader.position().byte();
+        let (start, line, parsed) = match csv_reader.read_byte_record(&mut record) {
+            Ok(false) => break,
+            //handle ok and failed deserialization
+            Ok(true) => {
+                let (start, line) = record
+                    .position()
+                    .map_or((start, 0), |position| (position.byte(), position.line()));
+                let parsed = record
+                    .deserialize::<TransactionRecord>(Some(&headers))
+                    .map_err(|e| RejectReason::Malformed(e.to_string()));
+                (start, line, parsed)
+            }
+            //io errors mean we cannot read any further
+            Err(e) if e.is_io_error() => return Err(e.into()),
+            //a record that fails reading should not brake our program
+            Err(e) => {
+                let line = e.position().map_or(0, |position| position.line());
+                (start, line, Err(RejectReason::Malformed(e.to_string())))
+            }
+        };
+
+        //keep the record exactly as it was in the input, without the line terminator
+        let end = csv_reader.position().byte();
+        let raw = String::from_utf8_lossy(csv_reader.get_ref().slice(start, end))
+            .trim_matches(|c| c == '\r' || c == '\n')
+            .to_owned();
+        csv_reader.get_mut().advance(end);
+        let input_hash = csv_reader.get_ref().hash();
+
+        //records up to the replayed line already changed the engine state
+        if collector.replayed(line, input_hash)? {
+            continue;
+        }
+
+        let outcome = match parsed {
+            //our transaction logic lives in the engine
+            Ok(data) => collector.engine.process_record(data),
+            Err(reason) => Outcome::Rejected(reason),
+        };
+        let fields = StringRecord::from_byte_record_lossy(record.clone());
+        collector.push(line, input_hash, outcome, &fields, &raw)?;
+
+        //the reader position is now at the start of the next record
+        //a checkpoint holds the whole engine state, so checkpoints are at least a tenth of the state apart,
+        //this keeps the work of saving them linear in the input size
+        if let Some((path, interval)) = checkpoint {
+            count += 1;
+            if count >= interval && count >= collector.engine.state_len() as u64 / STATE_PER_RECORD
+            {
+                count = 0;
+                collector.save_checkpoint(path, csv_reader.position(), input_hash)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+///Number of recorded bytes that are kept before they are dropped, dropping less often keeps moves cheap.
+const RECORDING_LIMIT: usize = 64 * 1024;
+
+///Reader that keeps the bytes it read, so rejected records can be written exactly as they were in the input.
+///Processed bytes are hashed so a checkpoint knows which input it was saved for.
+struct RecordingReader<R> {
+    ///Underlying reader.
+    inner: R,
+    ///Bytes read since `offset`.
+    buffer: Vec<u8>,
+    ///Input offset of the first byte in `buffer`.
+    offset: u64,
+    ///Hash of the input before `hashed`.
+    hash: InputHash,
+    ///Input offset up to which bytes are hashed.
+    hashed: u64,
+}
+
+impl<R> RecordingReader<R> {
+    ///Create a new reader that starts recording at offset zero.
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buffer: Vec::new(),
+            offset: 0,
+            hash: InputHash::default(),
+            hashed: 0,
+        }
+    }
+
+    ///Continue hashing at the current offset, the input before it has the given hash.
+    fn resume(&mut self, hash: InputHash) {
+        self.hash = hash;
+        self.hashed = self.offset;
+    }
+
+    ///Hash of the input before the end of the last processed record.
+    fn hash(&self) -> InputHash {
+        self.hash
+    }
+
+    ///Recorded bytes between two input offsets, empty when they were already dropped.
+    fn slice(&self, start: u64, end: u64) -> &[u8] {
+        if start < self.offset || end < start {
+            return &[];
+        }
+        let from = ((start - self.offset) as usize).min(self.buffer.len());
+        let to = ((end - self.offset) as usize).min(self.buffer.len());
+        &self.buffer[from..to]
+    }
+
+    ///Hash recorded bytes before an input offset and drop them once enough of them piled up.
+    fn advance(&mut self, until: u64) {
+        let mut hash = self.hash;
+        hash.update(self.slice(self.hashed, until));
+        self.hash = hash;
+        self.hashed = until;
+
+        let count = (until.saturating_sub(self.offset) as usize).min(self.buffer.len());
+        if count >= RECORDING_LIMIT {
+            self.buffer.drain(..count);
+            self.offset += count as u64;
+        }
+    }
+}
+
+impl<R: Read> Read for RecordingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.buffer.extend_from_slice(&buf[..count]);
+        Ok(count)
+    }
+}
+
+impl<R: Seek> Seek for RecordingReader<R> {
+    fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+        //recording starts over at the new offset
+        let offset = self.inner.seek(position)?;
+        self.buffer.clear();
+        self.offset = offset;
+        Ok(offset)
+    }
+}
+
+///Function for parsing newline delimited JSON from any reader and handling transactions.
+///Every non empty line must hold one transaction object with the same fields as the CSV input.
+pub fn parse_ndjson<R: Read>(input: R, engine: &mut Engine, outputs: Outputs) -> Result<Report> {
+    let mut collector = Collector::new(
+        engine,
+        &StringRecord::from(&TRANSACTION_FIELDS[..]),
+        outputs,
+    )?;
+
+    let mut input_hash = InputHash::default();
+    for (index, raw) in BufReader::new(input).lines().enumerate() {
+        //io errors mean we cannot read any further
+        let raw = raw?;
+        if raw.trim().is_empty() {
+            continue;
+        }
+
+        input_hash.update(raw.as_bytes());
+        input_hash.update(b"\n");
+        let value = serde_json::from_str::<Value>(&raw);
+        process_value(&mut collector, index as u64 + 1, input_hash, value, &raw)?;
+    }
+
+    collector.finish()
+}
+
+///Function for parsing a JSON array of transactions from any reader and handling transactions.
+///The whole array is loaded in memory, use NDJSON for large inputs.
+pub fn parse_json<R: Read>(input: R, engine: &mut Engine, outputs: Outputs) -> Result<Report> {
+    let mut collector = Collector::new(
+        engine,
+        &StringRecord::from(&TRANSACTION_FIELDS[..]),
+        outputs,
+    )?;
+
+    //a document that is not an array cannot be processed at all
+    let values: Vec<Value> = serde_json::from_reader(input)?;
+
+    //elements are hashed the way they are written to the dead letter output, so formatting does not matter
+    let mut input_hash = InputHash::default();
+    for (index, value) in values.into_iter().enumerate() {
+        let raw = value.to_string();
+        input_hash.update(raw.as_bytes());
+        input_hash.update(b"\n");
+        process_value(
+            &mut collector,
+            index as u64 + 1,
+            input_hash,
+            Ok(value),
+            &raw,
+        )?;
+    }
+
+    collector.finish()
+}
+
+///Deserialize one JSON value to a transaction and feed it to the engine.
+///The input up to and including the value has the given hash, blank lines are not part of it.
+fn process_value(
+    collector: &mut Collector,
+    line: u64,
+    input_hash: InputHash,
+    value: serde_json::Result<Value>,
+    raw: &str,
+) -> Result<()> {
+    //records up to the replayed line already changed the engine state
+    if collector.replayed(line, input_hash)? {
+        return Ok(());
+    }
+
+    let value = match value {
+        Ok(value) => value,
+        Err(e) => {
+            let outcome = Outcome::Rejected(RejectReason::Malformed(e.to_string()));
+            return collector.push(line, input_hash, outcome, &StringRecord::new(), raw);
         }
     };
 
-    //create a buffered reader from this file
-    let buff = BufReader::new(csv_file);
+    //keep the original fields for the dead letter output
+    let record: StringRecord = TRANSACTION_FIELDS
+        .iter()
+        .map(|field| match value.get(field) {
+            Some(Value::String(text)) => text.to_owned(),
+            Some(Value::Null) | None => String::new(),
+            Some(other) => other.to_string(),
+        })
+        .collect();
 
-    //read to csv using the reader
-    let mut csv_reader = uninit_builder.from_reader(buff);
+    let outcome = match serde_json::from_value::<TransactionRecord>(value) {
+        //our transaction logic lives in the engine
+        Ok(data) => collector.engine.process_record(data),
+        Err(e) => Outcome::Rejected(RejectReason::Malformed(e.to_string())),
+    };
+
+    collector.push(line, input_hash, outcome, &record, raw)
+}
 
-    //deserialize all record to Transaction struct
-    let csv_records = csv_reader.deserialize::<Transaction>();
+///Function for reading opening balances from a CSV in the same format the CSV export writes.
+///Fails on the first account whose total does not equal available plus held, or that is provided twice.
+pub fn read_accounts<R: Read>(input: R) -> Result<BTreeMap<AccountKey, AccountData>> {
+    let mut csv_reader = ReaderBuilder::new()
+        .has_headers(true)
+        .trim(Trim::All)
+        .from_reader(input);
 
-    //iterate over records
-    for record in csv_records {
-        //handle ok and failed deserialization
-        match record {
-            Ok(data) => {
-                //our transaction logic will be here
-                //we could write it to another function but since it's simple we should keep it inside this block for performance reasons
-                //if client id is not found then create a new client
-                if !accounts_map.contains_key(&data.client_id) {
-                    accounts_map.insert(data.client_id, AccountData::default());
-                }
-                //get a mutable reference to internal client data or throw error
-                let current_client_data = match accounts_map.get_mut(&data.client_id) {
-                    Some(client) => client,
-                    None => {
-                        return Err(std::io::Error::new(
-                            ErrorKind::Other,
-                            format!(
-                                "Cannot continue, cannot find client id '{}'.",
-                                &data.client_id
-                            ),
-                        ));
-                    }
-                };
-
-                //guard for locked accounts
-                if !current_client_data.locked {
-                    //match on transaction type
-                    match data.col_type {
-                        TransactionType::Deposit => {
-                            //deposit is a credit to the client's asset account, meaning it should increase the available
-                            //and total funds of the client account
-
-                            //guard for not overwritting transactions with a previously used id
-                            //this transactions should not be in our storage for now
-
-                            if let Some(transaction_id) = data.transaction_id {
-                                if !transactions_map.contains_key(&transaction_id) {
-                                    match data.amount {
-                                        Some(amount) => {
-                                            current_client_data.total += amount;
-                                            current_client_data.available += amount;
-                                        }
-                                        None => {
-                                            //TODO this technicaly is an error from csv, text says we should ignore it
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        TransactionType::Withdrawal => {
-                            // withdraw is a debit to the client's asset account, meaning it should decrease the available and
-                            // total funds of the client account
-
-                            //If a client does not have sufficient available funds the withdrawal should fail and the total amount
-                            //of funds should not change
-
-                            //guard for not overwritting transactions with a previously used id
-                            //this transactions should not be in our storage for now
-                            if let Some(transaction_id) = data.transaction_id {
-                                if !transactions_map.contains_key(&transaction_id) {
-                                    match data.amount {
-                                        Some(amount) => {
-                                            //guard for withdrawing funds
-                                            if (current_client_data.available - amount)
-                                                >= Decimal::new(0, 4)
-                                            {
-                                                current_client_data.available -= amount;
-                                                current_client_data.total -= amount;
-                                            }
-                                        }
-                                        None => {
-                                            //TODO this technicaly is an error from csv, text says we should ignore it
-                                        }
-                                    }
-                                }
-                            }
-                        }
-
-                        TransactionType::Dispute => {
-                            //guard for getting transaction id
-                            match data.transaction_id {
-                                Some(transaction_id) => {
-                                    //get the underlying transaction for doing this operation
-                                    match transactions_map.get_mut(&transaction_id) {
-                                        Some(transaction) => {
-                                            //guard for disputes referencing a transaction id
-                                            //that does not belong to the current client id
-                                            //or that is not already in a disputed state
-
-                                            if !transaction.disputed
-                                                && data.client_id == transaction.client_id
-                                            {
-                                                //if by some error the amount was not provided we can asume it's 0 because it will not change anything
-                                                let amount = transaction
-                                                    .amount
-                                                    .unwrap_or(Decimal::new(0, 4));
-
-                                                current_client_data.held += amount;
-                                                current_client_data.available -= amount;
-
-                                                //set transaction as disputed
-                                                transaction.disputed = true;
-                                            }
-                                        }
-                                        None => {
-                                            //TODO this technicaly is an error from csv, text says we should ignore it
-                                        }
-                                    }
-                                }
-                                None => {
-                                    //TODO this technicaly is an error from csv, text says we should ignore it
-                                }
-                            }
-                        }
-                        TransactionType::Resolve => {
-                            //guard for getting transaction id
-                            match data.transaction_id {
-                                Some(transaction_id) => {
-                                    //get the underlying transaction for doing this operation
-                                    match transactions_map.get_mut(&transaction_id) {
-                                        Some(transaction) => {
-                                            //check if transaction is under dispute and
-                                            //guard for resolves referencing a transaction id
-                                            //that does not belong to the current client id
-
-                                            if transaction.disputed
-                                                && data.client_id == transaction.client_id
-                                            {
-                                                //if by some error the amount was not provided we can asume it's 0 because it will not change anything
-                                                let amount = transaction
-                                                    .amount
-                                                    .unwrap_or(Decimal::new(0, 4));
-                                                current_client_data.held -= amount;
-                                                current_client_data.available += amount;
-
-                                                transaction.disputed = false;
-                                            }
-                                        }
-                                        None => {
-                                            //TODO this technicaly is an error from csv, text says we should ignore it
-                                        }
-                                    }
-                                }
-                                None => {
-                                    //TODO this technicaly is an error from csv, text says we should ignore it
-                                }
-                            }
-                        }
-                        TransactionType::Chargeback => {
-                            //guard for getting transaction id
-                            match data.transaction_id {
-                                Some(transaction_id) => {
-                                    //get the underlying transaction for doing this operation
-                                    match transactions_map.get_mut(&transaction_id) {
-                                        Some(transaction) => {
-                                            //check if transaction is under dispute and
-                                            //guard for resolves referencing a transaction id
-                                            //that does not belong to the current client id
-
-                                            if transaction.disputed
-                                                && data.client_id == transaction.client_id
-                                            {
-                                                //if by some error the amount was not provided we can asume it's 0 because it will not change anything
-                                                let amount = transaction
-                                                    .amount
-                                                    .unwrap_or(Decimal::new(0, 4));
-                                                //on chargeback take funds from held an total accounts
-                                                current_client_data.held -= amount;
-                                                current_client_data.total -= amount;
-                                                //set disputed to false
-                                                transaction.disputed = false;
-                                                //lock the client when chargeback occurs
-                                                current_client_data.locked = true;
-                                            }
-                                        }
-                                        None => {
-                                            //TODO this technicaly is an error from csv, text says we should ignore it
-                                        }
-                                    }
-                                }
-                                None => {
-                                    //TODO this technicaly is an error from csv, text says we should ignore it
-                                }
-                            }
-                        }
-                    }
-                }
-
-                //add this transaction to our storage if id was provided
-                //this should be the last step to avoid getting erronous results
-                //we should only add transactions that are withdrawal or deposit
-                //it doesn't make sense that there would be disputes over resolves or resolves over resolves, etc
-
-                match data.transaction_id {
-                    Some(id) => {
-                        //we should only insert valid transactions if their id does not collide
-                        //maybe we get a new transaction with an id that was already used before? this should never happen
-                        //but we should also guard for the possibility, if we dont take this into account we end up overwritting
-                        //the initial transaction
-
-                        match data.col_type {
-                            TransactionType::Deposit | TransactionType::Withdrawal => {
-                                if !transactions_map.contains_key(&id) {
-                                    transactions_map.insert(id, data.to_owned());
-                                }
-                            }
-
-                            _ => {}
-                        };
-                    }
-                    None => {
-                        //ignore it, no transaction id means no need of storing this
-                    }
-                }
-
-                //println!("{:#?}", data);
+    let headers = csv_reader.headers()?.clone();
+    let mut raw = StringRecord::new();
+    let mut accounts = BTreeMap::new();
+
+    while csv_reader.read_record(&mut raw)? {
+        //balances are taken as they are so any bad row stops the whole import
+        let line = raw.position().map_or(0, |position| position.line());
+        let record: AccountRecord = raw.deserialize(Some(&headers))?;
+
+        if record.total != record.available + record.held {
+            return Err(ValidationError::UnbalancedAccount(line).into());
+        }
+
+        let currency = record
+            .currency
+            .as_deref()
+            .map(Currency::new)
+            .transpose()
+            .map_err(|_| ValidationError::InvalidCurrency(line))?
+            .unwrap_or_default();
+        let key = AccountKey::new(ClientId(record.client), currency);
+        let data = AccountData {
+            available: record.available,
+            held: record.held,
+            total: record.total,
+            locked: record.locked,
+            ..AccountData::default()
+        };
+        if accounts.insert(key, data).is_some() {
+            return Err(ValidationError::DuplicateAccount(line).into());
+        }
+    }
+
+    Ok(accounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::open_output;
+    use crate::fees::FeeSchedule;
+    use crate::structs::TxId;
+    use rust_decimal::Decimal;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    ///Transactions touching deposits, withdrawals, disputes, chargebacks and admin actions.
+    const INPUT: &str = "type,client,tx,amount,reason
+deposit,1,1,10,
+deposit,2,2,5,
+withdrawal,1,3,2,
+dispute,2,2,,
+chargeback,2,2,,
+adjust,1,4,1.5,correction
+withdrawal,1,5,100,
+deposit,3,6,7,
+";
+
+    ///Path of a temporary file unique to one test.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("csv_parser_{}_{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    ///Process the input with a fresh engine, logging events to the given path.
+    fn process_logged(engine: &mut Engine, path: &Path) -> Report {
+        let mut event_log = EventLog::open(path).unwrap();
+        let outputs = Outputs {
+            event_log: Some(&mut event_log),
+            ..Outputs::default()
+        };
+        parse_csv(INPUT.as_bytes(), engine, outputs).unwrap()
+    }
+
+    #[test]
+    fn rejections_are_reported_with_their_line() {
+        let mut engine = Engine::new();
+        let report = parse_csv(INPUT.as_bytes(), &mut engine, Outputs::default()).unwrap();
+
+        assert_eq!(
+            report.rejections,
+            vec![Rejection {
+                line: 8,
+                reason: RejectReason::InsufficientFunds,
+            }]
+        );
+    }
+
+    #[test]
+    fn written_rejections_are_not_kept_in_the_report() {
+        let mut written = Vec::new();
+        let outputs = Outputs {
+            rejections: Some(&mut written),
+            ..Outputs::default()
+        };
+        let report = parse_csv(INPUT.as_bytes(), &mut Engine::new(), outputs).unwrap();
+
+        assert!(report.rejections.is_empty());
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "line 8: insufficient available funds\n"
+        );
+    }
+
+    #[test]
+    fn format_is_detected_from_the_first_non_whitespace_byte() {
+        let detect = |input: &str| detect_format(&mut input.as_bytes()).unwrap();
+
+        assert_eq!(detect("  \n[{\"type\":\"deposit\"}]"), InputFormat::Json);
+        assert_eq!(detect("{\"type\":\"deposit\"}\n"), InputFormat::Ndjson);
+        assert_eq!(detect("type,client,tx,amount\n"), InputFormat::Csv);
+        assert_eq!(detect(""), InputFormat::Csv);
+    }
+
+    #[test]
+    fn json_and_ndjson_input_give_the_state_of_csv_input() {
+        //string transaction ids are JSON strings
+        let tx = |id: u32| {
+            if cfg!(feature = "string-tx-ids") {
+                format!("\"{}\"", id)
+            } else {
+                id.to_string()
             }
-            Err(_) => {
-                //TODO if record fails deserialization this should not brake our program
-                //println!("{}", e)
+        };
+        let rows = [
+            format!(
+                r#"{{"type": "deposit", "client": 1, "tx": {}, "amount": "10"}}"#,
+                tx(1)
+            ),
+            format!(
+                r#"{{"type": "withdrawal", "client": 1, "tx": {}, "amount": 4}}"#,
+                tx(2)
+            ),
+            format!(
+                r#"{{"type": "withdrawal", "client": 1, "tx": {}, "amount": "100"}}"#,
+                tx(3)
+            ),
+        ];
+        let json = format!("[\n{}\n]", rows.join(",\n"));
+        let ndjson = format!("{}\n\n{}\n{}\nnot json\n", rows[0], rows[1], rows[2]);
+        let csv = "type,client,tx,amount\ndeposit,1,1,10\nwithdrawal,1,2,4\nwithdrawal,1,3,100\n";
+
+        let mut csv_engine = Engine::new();
+        parse(
+            csv.as_bytes(),
+            InputFormat::Csv,
+            &mut csv_engine,
+            Outputs::default(),
+        )
+        .unwrap();
+        let mut json_engine = Engine::new();
+        let json_report = parse(
+            json.as_bytes(),
+            InputFormat::Json,
+            &mut json_engine,
+            Outputs::default(),
+        )
+        .unwrap();
+        let mut ndjson_engine = Engine::new();
+        let ndjson_report = parse(
+            ndjson.as_bytes(),
+            InputFormat::Ndjson,
+            &mut ndjson_engine,
+            Outputs::default(),
+        )
+        .unwrap();
+
+        assert_eq!(json_engine.snapshot(), csv_engine.snapshot());
+        assert_eq!(ndjson_engine.snapshot(), csv_engine.snapshot());
+        //JSON arrays count elements, NDJSON counts lines including empty ones
+        let lines = |report: &Report| -> Vec<u64> {
+            report
+                .rejections
+                .iter()
+                .map(|rejection| rejection.line)
+                .collect()
+        };
+        assert_eq!(lines(&json_report), vec![3]);
+        assert_eq!(lines(&ndjson_report), vec![4, 5]);
+        assert!(matches!(
+            ndjson_report.rejections[1].reason,
+            RejectReason::Malformed(_)
+        ));
+    }
+
+    #[test]
+    fn json_document_that_is_not_an_array_is_refused() {
+        let result = parse_json(
+            "{\"type\": \"deposit\"}".as_bytes(),
+            &mut Engine::new(),
+            Outputs::default(),
+        );
+
+        assert!(matches!(result, Err(crate::Error::Json(_))));
+    }
+
+    #[test]
+    fn replay_rebuilds_the_state_of_a_straight_run() {
+        let path = temp_path("replay_round_trip.log");
+        let mut straight = Engine::new().with_event_tracking(true);
+        process_logged(&mut straight, &path);
+
+        let mut replayed = Engine::new();
+        let line = replayed
+            .replay(BufReader::new(File::open(&path).unwrap()))
+            .unwrap();
+
+        assert_eq!(line, 9);
+        assert_eq!(replayed.snapshot(), straight.snapshot());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replay_on_another_input_is_refused() {
+        let path = temp_path("replay_other_input.log");
+        let mut straight = Engine::new().with_event_tracking(true);
+        process_logged(&mut straight, &path);
+        let replayed = || {
+            let mut engine = Engine::new();
+            engine
+                .replay(BufReader::new(File::open(&path).unwrap()))
+                .unwrap();
+            engine
+        };
+
+        //a changed record before the replayed line would be skipped as if it was applied
+        let changed = INPUT.replace("deposit,2,2,5,", "deposit,2,2,6,");
+        let e = parse_csv(changed.as_bytes(), &mut replayed(), Outputs::default()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            ValidationError::ReplayMismatch(9).to_string()
+        );
+
+        let cut: String = INPUT
+            .lines()
+            .take(5)
+            .map(|line| line.to_owned() + "\n")
+            .collect();
+        let e = parse_csv(cut.as_bytes(), &mut replayed(), Outputs::default()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            ValidationError::ReplayMismatch(9).to_string()
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn resumed_run_keeps_rejected_transactions() {
+        let path = temp_path("resume_rejected.checkpoint");
+        let full = "type,client,tx,amount\nwithdrawal,1,1,5\ndeposit,1,2,10\ndispute,1,1,\n";
+        let cut = full.len() - "dispute,1,1,\n".len();
+
+        //the first run stops after the rejected withdrawal and the deposit
+        let mut first = Engine::new();
+        let outputs = Outputs::default();
+        parse_csv_resumable(
+            Cursor::new(&full[..cut]),
+            &mut first,
+            outputs,
+            &path,
+            None,
+            1,
+        )
+        .unwrap();
+
+        let mut resumed = Engine::new();
+        let saved = Checkpoint::load(&path).unwrap();
+        let outputs = Outputs::default();
+        let report =
+            parse_csv_resumable(Cursor::new(full), &mut resumed, outputs, &path, saved, 1).unwrap();
+
+        assert_eq!(
+            report.rejections,
+            vec![Rejection {
+                line: 4,
+                reason: RejectReason::NotApplied(TxId("1".parse().unwrap())),
+            }]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn checkpoint_of_another_input_is_refused() {
+        let path = temp_path("resume_mismatch.checkpoint");
+        let input = "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,10\n";
+        let other = "type,client,tx,amount\ndeposit,2,1,10\ndeposit,1,2,10\n";
+        let mut engine = Engine::new();
+        parse_csv_resumable(
+            Cursor::new(input),
+            &mut engine,
+            Outputs::default(),
+            &path,
+            None,
+            1,
+        )
+        .unwrap();
+
+        let saved = Checkpoint::load(&path).unwrap();
+        let mut engine = Engine::new();
+        let result = parse_csv_resumable(
+            Cursor::new(other),
+            &mut engine,
+            Outputs::default(),
+            &path,
+            saved,
+            1,
+        );
+
+        assert!(matches!(
+            result,
+            Err(crate::Error::Validation(
+                ValidationError::CheckpointMismatch(_)
+            ))
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+
+    ///Input with rejected records, admin actions, charged fees and events on both sides of a checkpoint.
+    const RESUMED: &str = "type,client,tx,amount,reason
+deposit,1,1,10,
+withdrawal,1,2,50,
+freeze,1,,,
+unlock,1,,,
+withdrawal,1,3,1,
+withdrawal,2,4,1,
+adjust,1,5,2,fix
+";
+
+    ///Output files of a resumable run.
+    struct OutputFiles {
+        checkpoint: PathBuf,
+        dead_letter: PathBuf,
+        audit: PathBuf,
+        fee_log: PathBuf,
+        event_log: PathBuf,
+    }
+
+    impl OutputFiles {
+        fn new(name: &str) -> Self {
+            Self {
+                checkpoint: temp_path(&format!("{}.checkpoint", name)),
+                dead_letter: temp_path(&format!("{}.dead_letter.csv", name)),
+                audit: temp_path(&format!("{}.audit.csv", name)),
+                fee_log: temp_path(&format!("{}.fees.csv", name)),
+                event_log: temp_path(&format!("{}.events.log", name)),
+            }
+        }
+
+        ///Process the input the way the command line does. A saved checkpoint is resumed, when `replay` is set
+        ///the event log of a crashed run is replayed instead.
+        fn run(&self, input: &str, replay: bool) -> Engine {
+            let fees = FeeSchedule::from_reader(r#"{"withdrawal_flat":"0.5"}"#.as_bytes()).unwrap();
+            let mut engine = Engine::new()
+                .with_fee_schedule(fees)
+                .with_event_tracking(true);
+            if replay && self.event_log.exists() {
+                engine
+                    .replay(BufReader::new(File::open(&self.event_log).unwrap()))
+                    .unwrap();
+            }
+
+            let saved = Checkpoint::load(&self.checkpoint).unwrap();
+            let lengths = match &saved {
+                Some(saved) => saved.outputs.clone(),
+                None => engine.replayed_outputs().clone(),
+            };
+            let mut dead_letter = open_output(&self.dead_letter, lengths.dead_letter).unwrap();
+            let mut audit = open_output(&self.audit, lengths.audit).unwrap();
+            let mut fee_log = open_output(&self.fee_log, lengths.fee_log).unwrap();
+            let mut event_log = match lengths.event_log {
+                Some(length) => EventLog::open_truncated(&self.event_log, length),
+                None => EventLog::open(&self.event_log),
+            }
+            .unwrap();
+
+            let outputs = Outputs {
+                rejections: None,
+                dead_letter: Some(&mut dead_letter),
+                audit: Some(&mut audit),
+                fee_log: Some(&mut fee_log),
+                event_log: Some(&mut event_log),
+                lengths,
+            };
+            if replay {
+                parse_csv(Cursor::new(input), &mut engine, outputs).unwrap();
+            } else {
+                parse_csv_resumable(
+                    Cursor::new(input),
+                    &mut engine,
+                    outputs,
+                    &self.checkpoint,
+                    saved,
+                    2,
+                )
+                .unwrap();
+            }
+            engine
+        }
+
+        ///Contents of the dead letter, audit, fee and event log files.
+        fn contents(&self) -> Vec<String> {
+            [
+                &self.dead_letter,
+                &self.audit,
+                &self.fee_log,
+                &self.event_log,
+            ]
+            .iter()
+            .map(|path| fs::read_to_string(path).unwrap())
+            .collect()
+        }
+
+        fn remove(&self) {
+            for path in [
+                &self.checkpoint,
+                &self.dead_letter,
+                &self.audit,
+                &self.fee_log,
+                &self.event_log,
+            ] {
+                //runs that are recovered by replay save no checkpoint
+                let _ = fs::remove_file(path);
             }
         }
     }
 
-    //println!("{:#?}", accounts_map);
-    //println!("{:#?}", transactions_map);
+    #[test]
+    fn resumed_run_writes_the_outputs_of_a_straight_run() {
+        let straight = OutputFiles::new("straight");
+        let straight_engine = straight.run(RESUMED, false);
+
+        //the interrupted run wrote the fifth record after it's last checkpoint
+        let resumed = OutputFiles::new("resumed");
+        let cut = RESUMED.match_indices('\n').nth(5).unwrap().0 + 1;
+        resumed.run(&RESUMED[..cut], false);
+        let resumed_engine = resumed.run(RESUMED, false);
 
-    //at this point csv parsing and transactions engine should be finished
-    //we need to export our data to valid CSV as stdout
-    export_to_stdout(&accounts_map);
+        assert_eq!(resumed_engine.snapshot(), straight_engine.snapshot());
+        assert_eq!(resumed.contents(), straight.contents());
+        //every output has exactly one header
+        for contents in &straight.contents()[..3] {
+            assert_eq!(contents.matches("client").count(), 1);
+        }
+        straight.remove();
+        resumed.remove();
+    }
+
+    #[test]
+    fn replayed_records_are_not_applied_again() {
+        //the dispute is rejected but creates the account of client 3
+        let input = format!("{}dispute,3,9,,\ndeposit,1,10,1,\n", RESUMED);
+        let straight = OutputFiles::new("replay_straight");
+        let straight_engine = straight.run(&input, true);
+
+        //the crashed run wrote the rows of every record but only synced some of it's events
+        for synced in [0, 4, 8].iter() {
+            let crashed = OutputFiles::new(&format!("replay_crashed_{}", synced));
+            crashed.run(&input, true);
+            let events: String = fs::read_to_string(&crashed.event_log)
+                .unwrap()
+                .lines()
+                .take(*synced)
+                .map(|line| line.to_owned() + "\n")
+                .collect();
+            fs::write(&crashed.event_log, events).unwrap();
+
+            let recovered_engine = crashed.run(&input, true);
+            assert_eq!(recovered_engine.snapshot(), straight_engine.snapshot());
+            assert_eq!(crashed.contents(), straight.contents());
+            crashed.remove();
+        }
+        straight.remove();
+    }
 
-    return Ok(());
+    #[test]
+    fn events_carry_the_input_line() {
+        let path = temp_path("event_lines.log");
+        let mut engine = Engine::new().with_event_tracking(true);
+        process_logged(&mut engine, &path);
+
+        let lines: Vec<u64> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<crate::wal::Event>(line)
+                    .unwrap()
+                    .line
+            })
+            .collect();
+
+        //the rejected withdrawal on line 8 is logged because it is kept as rejected
+        assert_eq!(lines, vec![2, 3, 4, 5, 6, 7, 8, 9]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_currency_is_rejected() {
+        let input = "type,client,tx,amount,currency
+deposit,1,1,10,eur
+deposit,1,2,10,\"E,UR\"
+";
+        let mut engine = Engine::new();
+        let report = parse_csv(input.as_bytes(), &mut engine, Outputs::default()).unwrap();
+
+        assert_eq!(
+            report.rejections,
+            vec![Rejection {
+                line: 3,
+                reason: RejectReason::InvalidCurrency("E,UR".to_string()),
+            }]
+        );
+        assert_eq!(engine.accounts().len(), 1);
+    }
+
+    #[test]
+    fn opening_balance_with_an_invalid_currency_is_refused() {
+        let input = "client,currency,available,held,total,locked
+1,EUR,1,0,1,false
+1,EURO,1,0,1,false
+";
+        let e = read_accounts(input.as_bytes()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            ValidationError::InvalidCurrency(3).to_string()
+        );
+    }
+
+    #[test]
+    fn opening_balances_are_read_in_the_export_format() {
+        let input = "client,available,held,total,locked
+1, 1.5, 2, 3.5, false
+2, -1, 0, -1, true
+";
+        let accounts = read_accounts(input.as_bytes()).unwrap();
+        let rows: Vec<_> = accounts
+            .iter()
+            .map(|(key, data)| (key.client.to_string(), data.total, data.locked))
+            .collect();
+
+        assert_eq!(
+            rows,
+            vec![
+                ("1".to_string(), Decimal::new(35, 1), false),
+                ("2".to_string(), Decimal::new(-1, 0), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn unbalanced_or_repeated_opening_balance_is_refused() {
+        let unbalanced = "client,available,held,total,locked
+1,1,0,1,false
+2,1,1,3,false
+";
+        let e = read_accounts(unbalanced.as_bytes()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            ValidationError::UnbalancedAccount(3).to_string()
+        );
+
+        let repeated = "client,currency,available,held,total,locked
+1,EUR,1,0,1,false
+1,USD,1,0,1,false
+1,eur,2,0,2,false
+";
+        let e = read_accounts(repeated.as_bytes()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            ValidationError::DuplicateAccount(4).to_string()
+        );
+    }
 }
@@ -0,0 +1,233 @@
+//! Module for versioned snapshots of the engine state, used to continue processing where a previous run stopped.
+
+use crate::error::ValidationError;
+use crate::structs::AccountKey;
+use crate::structs::Currency;
+use crate::structs::StoredTransaction;
+use crate::structs::Transaction;
+use crate::structs::TxId;
+use crate::Result;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+
+///Version written to new snapshots, older or newer versions are refused on load.
+pub const SNAPSHOT_VERSION: u64 = 1;
+
+///Account as stored in a snapshot. Funds are not rounded, credit limits come from the configuration on restore.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SnapshotAccount {
+    ///Client id and currency of the account.
+    #[serde(flatten)]
+    pub key: AccountKey,
+    ///Available funds.
+    pub available: Decimal,
+    ///Held funds.
+    pub held: Decimal,
+    ///Total funds.
+    pub total: Decimal,
+    ///Locked state.
+    pub locked: bool,
+}
+
+///Stored transaction together with it's id.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SnapshotTransaction {
+    ///Transaction id.
+    pub tx: TxId,
+    ///Client, amount and dispute state of the transaction.
+    #[serde(flatten)]
+    pub transaction: StoredTransaction,
+}
+
+///Full engine state: accounts, disputable transactions with their dispute state, rejected transactions and collected
+///fees. The audit and fee trails are not part of a snapshot.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    ///Format version, always `SNAPSHOT_VERSION` for snapshots written by this crate.
+    pub version: u64,
+    ///Accounts ordered by client id and currency.
+    pub accounts: Vec<SnapshotAccount>,
+    ///Stored transactions ordered by transaction id.
+    pub transactions: Vec<SnapshotTransaction>,
+    ///Fees collected in the house accounts, indexed by currency.
+    #[serde(default)]
+    pub house_accounts: BTreeMap<Currency, Decimal>,
+    ///Rejected deposits, withdrawals and transfers ordered by transaction id, these cannot be disputed.
+    #[serde(default)]
+    pub rejected: Vec<Transaction>,
+}
+
+impl Snapshot {
+    ///Read a snapshot written as JSON, the version is checked before anything else.
+    pub fn read<R: Read>(reader: R) -> Result<Self> {
+        let value: Value = serde_json::from_reader(reader)?;
+        Self::from_value(value)
+    }
+
+    ///Convert a snapshot from a JSON value, the version is checked before anything else.
+    pub fn from_value(value: Value) -> Result<Self> {
+        match value.get("version").and_then(Value::as_u64) {
+            Some(SNAPSHOT_VERSION) => Ok(serde_json::from_value(value)?),
+            version => Err(ValidationError::UnsupportedSnapshotVersion(
+                version.map_or_else(|| "missing".to_owned(), |version| version.to_string()),
+            )
+            .into()),
+        }
+    }
+
+    ///Write the snapshot as JSON.
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<()> {
+        serde_json::to_writer(&mut writer, self)?;
+        writeln!(writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    ///Save the snapshot to a file without leaving a partly written snapshot behind, see `save_json`.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        save_json(self, path)
+    }
+}
+
+///Save a value as JSON to a file. It is written next to the file first and then renamed over it,
+///so a crash while saving leaves the previous file intact.
+pub(crate) fn save_json<T: Serialize>(value: &T, path: &Path) -> Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    serde_json::to_writer(&mut writer, value)?;
+    writeln!(writer)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credit::CreditLimits;
+    use crate::engine::Engine;
+    use crate::engine::Outcome;
+    use crate::engine::RejectReason;
+    use crate::structs::Amount;
+    use crate::structs::ClientId;
+    use std::convert::TryFrom;
+    use std::path::PathBuf;
+
+    ///Path of a temporary file unique to one test.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("csv_parser_{}_{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn tx(id: u32) -> TxId {
+        TxId(id.to_string().parse().unwrap())
+    }
+
+    fn amount(value: i64) -> Amount {
+        Amount::try_from(Decimal::new(value, 0)).unwrap()
+    }
+
+    ///Engine with a disputed deposit, a rejected withdrawal and a locked client.
+    fn engine() -> Engine {
+        let mut engine = Engine::new();
+        for data in [
+            Transaction::Deposit {
+                client: ClientId(1),
+                tx: tx(1),
+                amount: amount(10),
+            },
+            Transaction::Dispute {
+                client: ClientId(1),
+                tx: tx(1),
+            },
+            Transaction::Withdrawal {
+                client: ClientId(2),
+                tx: tx(2),
+                amount: amount(5),
+            },
+            Transaction::Freeze {
+                client: ClientId(3),
+            },
+        ]
+        .iter()
+        {
+            engine.process(data.clone());
+        }
+        engine
+    }
+
+    #[test]
+    fn saved_snapshot_reads_back_the_same() {
+        let path = temp_path("snapshot_round_trip.json");
+        let snapshot = engine().snapshot();
+        assert_eq!(snapshot.rejected.len(), 1);
+
+        snapshot.save(&path).unwrap();
+        snapshot.save(&path).unwrap();
+        let saved = Snapshot::read(File::open(&path).unwrap()).unwrap();
+        assert_eq!(saved, snapshot);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn restored_engine_continues_with_the_same_state() {
+        let original = engine();
+        let mut restored = Engine::new().with_snapshot(original.snapshot());
+
+        assert_eq!(restored.snapshot(), original.snapshot());
+        //the rejected withdrawal still cannot be disputed
+        assert_eq!(
+            restored.process(Transaction::Dispute {
+                client: ClientId(2),
+                tx: tx(2),
+            }),
+            Outcome::Rejected(RejectReason::NotApplied(tx(2)))
+        );
+        assert_eq!(
+            restored.process(Transaction::Resolve {
+                client: ClientId(1),
+                tx: tx(1),
+            }),
+            Outcome::Applied
+        );
+    }
+
+    #[test]
+    fn restored_accounts_get_the_configured_credit_limits() {
+        let limits = CreditLimits::new()
+            .with_default(Decimal::new(3, 0))
+            .unwrap();
+        let restored = Engine::new()
+            .with_credit_limits(limits)
+            .with_snapshot(engine().snapshot());
+
+        assert!(restored
+            .accounts()
+            .values()
+            .all(|data| data.credit_limit == Decimal::new(3, 0)));
+    }
+
+    #[test]
+    fn snapshot_of_another_version_is_refused() {
+        let e = Snapshot::read(r#"{"version": 2, "accounts": []}"#.as_bytes()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            ValidationError::UnsupportedSnapshotVersion("2".to_owned()).to_string()
+        );
+        assert!(Snapshot::read(r#"{"accounts": []}"#.as_bytes()).is_err());
+    }
+}
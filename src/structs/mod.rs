@@ -1,10 +1,15 @@
 //! Module for storing structs and enums that are used in other modules.
 
+use crate::validation::AmountError;
 use rust_decimal::Decimal;
 use serde::Deserialize;
+use serde::Serialize;
+use serde::Serializer;
+use std::convert::TryFrom;
+use std::fmt;
 
 ///Transaction type enum. Instruct serde how to deserialize by renaming to lowercase.
-#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     Deposit,
@@ -12,48 +17,643 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    ///Move of funds from one client to another.
+    Transfer,
+    ///Admin action that unlocks an account.
+    Unlock,
+    ///Admin action that locks an account.
+    Freeze,
+    ///Admin action that corrects available and total funds by a signed amount.
+    Adjust,
 }
-///Transaction structure. Provides serde crate with field names in CSV.
+///Raw transaction record. Provides serde crate with field names in CSV.
+///Converted to a `Transaction` before it reaches the engine.
 #[derive(Debug, Deserialize, PartialEq, Clone)]
-pub struct Transaction {
+pub struct TransactionRecord {
     ///Type for transactions.
     #[serde(rename = "type")]
     pub col_type: TransactionType,
     ///Client id inside transaction.
     #[serde(rename = "client")]
-    pub client_id: u16,
+    pub client_id: ClientIdValue,
     ///Transaction id data field wrapped in option type.
     #[serde(rename = "tx")]
-    pub transaction_id: Option<u32>,
+    pub transaction_id: Option<TxIdValue>,
     ///Amount data field wrapped in option type.
     #[serde(rename = "amount")]
     pub amount: Option<Decimal>,
+    ///Receiving client of a transfer, this column is optional in the input.
+    #[serde(rename = "to_client", default)]
+    pub to_client: Option<ClientIdValue>,
+    ///Currency of the transaction, this column is optional in the input.
+    #[serde(rename = "currency", default)]
+    pub currency: Option<String>,
+    ///Reason for an admin adjustment, this column is optional in the input.
+    #[serde(rename = "reason", default)]
+    pub reason: Option<String>,
+}
+
+///Raw account record, the same format the CSV export writes. Used to seed opening balances.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct AccountRecord {
+    ///Client id of the account.
+    pub client: ClientIdValue,
+    ///Currency of the account, this column is optional in the input.
     #[serde(default)]
-    ///Disputed flag set to "false" on init for every new transaction.
-    pub disputed: bool,
+    pub currency: Option<String>,
+    ///Available funds.
+    pub available: Decimal,
+    ///Held funds.
+    pub held: Decimal,
+    ///Total funds, must equal available plus held.
+    pub total: Decimal,
+    ///Locked state.
+    pub locked: bool,
+}
+
+///Underlying client id type, `u64` with the `wide-client-ids` feature.
+#[cfg(not(feature = "wide-client-ids"))]
+pub type ClientIdValue = u16;
+///Underlying client id type, `u64` with the `wide-client-ids` feature.
+#[cfg(feature = "wide-client-ids")]
+pub type ClientIdValue = u64;
+
+///Underlying transaction id type, an opaque string with the `string-tx-ids` feature.
+#[cfg(not(feature = "string-tx-ids"))]
+pub type TxIdValue = u32;
+///Underlying transaction id type, an opaque string with the `string-tx-ids` feature.
+#[cfg(feature = "string-tx-ids")]
+pub type TxIdValue = String;
+
+///Client id.
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ClientId(pub ClientIdValue);
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+///Transaction id. Not `Copy` so string ids can be used.
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TxId(pub TxIdValue);
+
+impl fmt::Display for TxId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+///Currency code of 3 letters, stored uppercase. Empty when the input has no currency.
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Currency(String);
+
+impl Currency {
+    ///Create a currency from a code, surrounding whitespace is removed and an empty code means no currency.
+    ///Codes other than 3 ASCII letters are refused, this keeps them safe to write in every export.
+    pub fn new(code: &str) -> Result<Self, RecordError> {
+        let code = code.trim();
+        if !code.is_empty() && (code.len() != 3 || !code.bytes().all(|b| b.is_ascii_alphabetic())) {
+            return Err(RecordError::InvalidCurrency(code.to_owned()));
+        }
+        Ok(Self(code.to_ascii_uppercase()))
+    }
+
+    ///Check if no currency was provided.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl TryFrom<String> for Currency {
+    type Error = RecordError;
+
+    fn try_from(code: String) -> Result<Self, Self::Error> {
+        Self::new(&code)
+    }
+}
+
+impl From<Currency> for String {
+    fn from(currency: Currency) -> Self {
+        currency.0
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+///Key of an account, a client has one account per currency.
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Serialize, Deserialize)]
+pub struct AccountKey {
+    ///Client that owns the account.
+    pub client: ClientId,
+    ///Currency of the account funds.
+    #[serde(default, skip_serializing_if = "Currency::is_empty")]
+    pub currency: Currency,
+}
+
+impl AccountKey {
+    ///Create a new account key.
+    pub fn new(client: ClientId, currency: Currency) -> Self {
+        Self { client, currency }
+    }
+}
+
+///Amount of a deposit or withdrawal, always larger than zero.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "Decimal", into = "Decimal")]
+pub struct Amount(Decimal);
+
+impl Amount {
+    ///Create a new amount or return an error if it is not positive.
+    pub fn new(value: Decimal) -> Result<Self, AmountError> {
+        if value <= Decimal::ZERO {
+            return Err(AmountError::NotPositive(value));
+        }
+        Ok(Self(value))
+    }
+
+    ///Underlying decimal value.
+    pub fn value(self) -> Decimal {
+        self.0
+    }
+}
+
+impl TryFrom<Decimal> for Amount {
+    type Error = AmountError;
+
+    fn try_from(value: Decimal) -> Result<Self, Self::Error> {
+        Amount::new(value)
+    }
+}
+
+impl From<Amount> for Decimal {
+    fn from(amount: Amount) -> Self {
+        amount.0
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+///Validated transaction. Every variant carries only the fields it needs.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Transaction {
+    ///Credit to the client's asset account.
+    Deposit {
+        client: ClientId,
+        tx: TxId,
+        amount: Amount,
+    },
+    ///Debit to the client's asset account.
+    Withdrawal {
+        client: ClientId,
+        tx: TxId,
+        amount: Amount,
+    },
+    ///Debit to the client's asset account and credit to the receiving client's asset account.
+    Transfer {
+        client: ClientId,
+        to_client: ClientId,
+        tx: TxId,
+        amount: Amount,
+    },
+    ///Claim that a previous deposit, withdrawal or transfer was erroneous.
+    Dispute { client: ClientId, tx: TxId },
+    ///Resolution of a dispute, held funds are released.
+    Resolve { client: ClientId, tx: TxId },
+    ///Final state of a dispute, held funds are withdrawn.
+    Chargeback { client: ClientId, tx: TxId },
+    ///Admin action that unlocks an account.
+    Unlock { client: ClientId },
+    ///Admin action that locks an account.
+    Freeze { client: ClientId },
+    ///Admin correction of available and total funds. The amount is signed and never zero.
+    Adjust {
+        client: ClientId,
+        amount: Decimal,
+        reason: String,
+    },
+}
+
+impl Transaction {
+    ///Client id of the transaction.
+    pub fn client(&self) -> ClientId {
+        match self {
+            Transaction::Deposit { client, .. }
+            | Transaction::Withdrawal { client, .. }
+            | Transaction::Transfer { client, .. }
+            | Transaction::Dispute { client, .. }
+            | Transaction::Resolve { client, .. }
+            | Transaction::Chargeback { client, .. }
+            | Transaction::Unlock { client }
+            | Transaction::Freeze { client }
+            | Transaction::Adjust { client, .. } => *client,
+        }
+    }
+
+    ///Transaction id, for disputes, resolves and chargebacks this is the referenced transaction.
+    ///Admin actions do not have one.
+    pub fn tx(&self) -> Option<&TxId> {
+        match self {
+            Transaction::Deposit { tx, .. }
+            | Transaction::Withdrawal { tx, .. }
+            | Transaction::Transfer { tx, .. }
+            | Transaction::Dispute { tx, .. }
+            | Transaction::Resolve { tx, .. }
+            | Transaction::Chargeback { tx, .. } => Some(tx),
+            _ => None,
+        }
+    }
+
+    ///Check if this is an admin action.
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            Transaction::Unlock { .. } | Transaction::Freeze { .. } | Transaction::Adjust { .. }
+        )
+    }
+
+    ///Amount of a deposit, withdrawal or transfer.
+    pub fn amount(&self) -> Option<Amount> {
+        match self {
+            Transaction::Deposit { amount, .. }
+            | Transaction::Withdrawal { amount, .. }
+            | Transaction::Transfer { amount, .. } => Some(*amount),
+            _ => None,
+        }
+    }
+
+    ///Type of the transaction.
+    pub fn col_type(&self) -> TransactionType {
+        match self {
+            Transaction::Deposit { .. } => TransactionType::Deposit,
+            Transaction::Withdrawal { .. } => TransactionType::Withdrawal,
+            Transaction::Transfer { .. } => TransactionType::Transfer,
+            Transaction::Dispute { .. } => TransactionType::Dispute,
+            Transaction::Resolve { .. } => TransactionType::Resolve,
+            Transaction::Chargeback { .. } => TransactionType::Chargeback,
+            Transaction::Unlock { .. } => TransactionType::Unlock,
+            Transaction::Freeze { .. } => TransactionType::Freeze,
+            Transaction::Adjust { .. } => TransactionType::Adjust,
+        }
+    }
+}
+
+///Reasons a raw record cannot be converted to a transaction.
+#[derive(Debug, PartialEq, Clone)]
+pub enum RecordError {
+    ///Transaction id was not provided.
+    MissingTransactionId,
+    ///Amount was not provided for a deposit or withdrawal.
+    MissingAmount,
+    ///Amount of a deposit or withdrawal is not positive, or an adjustment is zero.
+    InvalidAmount(AmountError),
+    ///Reason was not provided for an adjustment.
+    MissingReason,
+    ///Receiving client was not provided for a transfer.
+    MissingRecipient,
+    ///Receiving client of a transfer is the sending client.
+    SelfTransfer,
+    ///Currency code is not 3 letters.
+    InvalidCurrency(String),
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordError::MissingTransactionId => write!(f, "missing transaction id"),
+            RecordError::MissingAmount => write!(f, "missing amount"),
+            RecordError::InvalidAmount(e) => write!(f, "invalid amount: {}", e),
+            RecordError::MissingReason => write!(f, "missing reason"),
+            RecordError::MissingRecipient => write!(f, "missing receiving client"),
+            RecordError::SelfTransfer => write!(f, "transfer to the sending client"),
+            RecordError::InvalidCurrency(code) => write!(f, "invalid currency '{}'", code),
+        }
+    }
+}
+
+impl std::error::Error for RecordError {}
+
+impl TryFrom<TransactionRecord> for Transaction {
+    type Error = RecordError;
+
+    ///Amounts provided on disputes, resolves and chargebacks are ignored, so are ids on admin actions.
+    ///The currency is not part of a transaction, the engine takes it separately.
+    fn try_from(record: TransactionRecord) -> Result<Self, Self::Error> {
+        let TransactionRecord {
+            col_type,
+            client_id,
+            transaction_id,
+            amount,
+            to_client,
+            reason,
+            ..
+        } = record;
+
+        let client = ClientId(client_id);
+        let tx = transaction_id
+            .map(TxId)
+            .ok_or(RecordError::MissingTransactionId);
+        let positive = || -> Result<Amount, RecordError> {
+            let value = amount.ok_or(RecordError::MissingAmount)?;
+            Amount::new(value).map_err(RecordError::InvalidAmount)
+        };
+
+        Ok(match col_type {
+            TransactionType::Deposit => Transaction::Deposit {
+                client,
+                tx: tx?,
+                amount: positive()?,
+            },
+            TransactionType::Withdrawal => Transaction::Withdrawal {
+                client,
+                tx: tx?,
+                amount: positive()?,
+            },
+            TransactionType::Transfer => {
+                let to_client = to_client
+                    .map(ClientId)
+                    .ok_or(RecordError::MissingRecipient)?;
+                if to_client == client {
+                    return Err(RecordError::SelfTransfer);
+                }
+                Transaction::Transfer {
+                    client,
+                    to_client,
+                    tx: tx?,
+                    amount: positive()?,
+                }
+            }
+            TransactionType::Dispute => Transaction::Dispute { client, tx: tx? },
+            TransactionType::Resolve => Transaction::Resolve { client, tx: tx? },
+            TransactionType::Chargeback => Transaction::Chargeback { client, tx: tx? },
+            TransactionType::Unlock => Transaction::Unlock { client },
+            TransactionType::Freeze => Transaction::Freeze { client },
+            TransactionType::Adjust => {
+                let amount = amount.ok_or(RecordError::MissingAmount)?;
+                if amount == Decimal::ZERO {
+                    return Err(RecordError::InvalidAmount(AmountError::Zero));
+                }
+                Transaction::Adjust {
+                    client,
+                    amount,
+                    reason: reason
+                        .filter(|reason| !reason.is_empty())
+                        .ok_or(RecordError::MissingReason)?,
+                }
+            }
+        })
+    }
+}
+
+///Deposit, withdrawal or transfer stored by the engine so it can be disputed later.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct StoredTransaction {
+    ///Client that owns the transaction, for transfers this is the sending client.
+    pub client_id: ClientId,
+    ///Receiving client of a transfer.
+    pub to_client: Option<ClientId>,
+    ///Either deposit, withdrawal or transfer.
+    pub col_type: TransactionType,
+    ///Currency of the moved funds, disputes are applied in this currency.
+    pub currency: Currency,
+    ///Amount that was moved.
+    pub amount: Amount,
+    ///Dispute state set to `DisputeState::None` on init for every new transaction.
+    pub dispute_state: DisputeState,
+}
+
+///Dispute lifecycle of a stored transaction.
+///A transaction can be disputed once, the dispute then ends either resolved or charged back.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisputeState {
+    ///Transaction was never disputed.
+    None,
+    ///Transaction is under dispute and it's funds are held.
+    Disputed,
+    ///Dispute was resolved, this is a terminal state.
+    Resolved,
+    ///Dispute ended with a chargeback, this is a terminal state.
+    ChargedBack,
+}
+
+///New transactions were never disputed.
+impl Default for DisputeState {
+    fn default() -> Self {
+        DisputeState::None
+    }
+}
+
+impl DisputeState {
+    ///Return the state reached by applying a dispute, resolve or chargeback, or `None` if the transition is not allowed.
+    pub fn next(self, col_type: &TransactionType) -> Option<DisputeState> {
+        match (self, col_type) {
+            (DisputeState::None, TransactionType::Dispute) => Some(DisputeState::Disputed),
+            (DisputeState::Disputed, TransactionType::Resolve) => Some(DisputeState::Resolved),
+            (DisputeState::Disputed, TransactionType::Chargeback) => {
+                Some(DisputeState::ChargedBack)
+            }
+            _ => None,
+        }
+    }
+
+    ///Check if the dispute lifecycle is finished.
+    pub fn is_terminal(self) -> bool {
+        matches!(self, DisputeState::Resolved | DisputeState::ChargedBack)
+    }
 }
 
 ///Account data structure for storing account details.
-#[derive(Debug)]
+///Serialized funds are rounded the same way as the CSV export.
+#[derive(Debug, Clone, Serialize)]
 pub struct AccountData {
     ///Available funds associated with a client id.
+    #[serde(serialize_with = "serialize_rounded")]
     pub available: Decimal,
     ///Help funds associated with a client id.
+    #[serde(serialize_with = "serialize_rounded")]
     pub held: Decimal,
     ///Total funds associated with a client id.
+    #[serde(serialize_with = "serialize_rounded")]
     pub total: Decimal,
     ///Locked state for a client id.
     pub locked: bool,
+    ///Credit limit of the client, shared by all of it's accounts and exported as the remaining credit.
+    #[serde(skip)]
+    pub credit_limit: Decimal,
+}
+
+impl AccountData {
+    ///Create an empty account that can overdraw it's available funds by the credit limit.
+    pub fn with_credit_limit(credit_limit: Decimal) -> Self {
+        Self {
+            credit_limit,
+            ..Self::default()
+        }
+    }
 }
 
 ///Implement default for AccountData. This get's stored when parsing a new client id.
 impl Default for AccountData {
     fn default() -> Self {
-        return Self {
+        Self {
             available: Decimal::new(0, 4),
             held: Decimal::new(0, 4),
             total: Decimal::new(0, 4),
             locked: false,
-        };
+            credit_limit: Decimal::ZERO,
+        }
+    }
+}
+
+///Round a value to 4 places past the decimal point, zero is always written as "0".
+pub(crate) fn rounded(value: Decimal) -> Decimal {
+    if value == Decimal::ZERO {
+        Decimal::ZERO
+    } else {
+        value.round_dp(4)
+    }
+}
+
+///Serialize a funds field using the export rounding rules.
+fn serialize_rounded<S: Serializer>(value: &Decimal, serializer: S) -> Result<S::Ok, S::Error> {
+    Serialize::serialize(&rounded(*value), serializer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///Record with only the type and client filled in.
+    fn record(col_type: TransactionType) -> TransactionRecord {
+        TransactionRecord {
+            col_type,
+            client_id: 1,
+            transaction_id: None,
+            amount: None,
+            to_client: None,
+            currency: None,
+            reason: None,
+        }
+    }
+
+    fn tx_id(id: &str) -> TxIdValue {
+        id.parse().unwrap()
+    }
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn deposit_is_built_from_a_complete_record() {
+        let mut deposit = record(TransactionType::Deposit);
+        deposit.transaction_id = Some(tx_id("7"));
+        deposit.amount = Some(dec("1.5"));
+
+        assert_eq!(
+            Transaction::try_from(deposit),
+            Ok(Transaction::Deposit {
+                client: ClientId(1),
+                tx: TxId(tx_id("7")),
+                amount: Amount::new(dec("1.5")).unwrap(),
+            })
+        );
+    }
+
+    #[test]
+    fn incomplete_records_are_refused() {
+        let mut deposit = record(TransactionType::Deposit);
+        assert_eq!(
+            Transaction::try_from(deposit.clone()),
+            Err(RecordError::MissingTransactionId)
+        );
+
+        deposit.transaction_id = Some(tx_id("7"));
+        assert_eq!(
+            Transaction::try_from(deposit.clone()),
+            Err(RecordError::MissingAmount)
+        );
+
+        deposit.amount = Some(dec("-1"));
+        assert_eq!(
+            Transaction::try_from(deposit),
+            Err(RecordError::InvalidAmount(AmountError::NotPositive(dec(
+                "-1"
+            ))))
+        );
+
+        assert_eq!(
+            Transaction::try_from(record(TransactionType::Dispute)),
+            Err(RecordError::MissingTransactionId)
+        );
+    }
+
+    #[test]
+    fn amount_of_a_dispute_is_ignored() {
+        let mut dispute = record(TransactionType::Dispute);
+        dispute.transaction_id = Some(tx_id("7"));
+        dispute.amount = Some(dec("-1"));
+
+        assert_eq!(
+            Transaction::try_from(dispute),
+            Ok(Transaction::Dispute {
+                client: ClientId(1),
+                tx: TxId(tx_id("7")),
+            })
+        );
+    }
+
+    #[test]
+    fn adjustment_needs_a_reason_and_an_amount_that_is_not_zero() {
+        let mut adjust = record(TransactionType::Adjust);
+        adjust.amount = Some(dec("-2"));
+        adjust.reason = Some(String::new());
+        assert_eq!(
+            Transaction::try_from(adjust.clone()),
+            Err(RecordError::MissingReason)
+        );
+
+        adjust.reason = Some("bank error".to_string());
+        assert_eq!(
+            Transaction::try_from(adjust.clone()),
+            Ok(Transaction::Adjust {
+                client: ClientId(1),
+                amount: dec("-2"),
+                reason: "bank error".to_string(),
+            })
+        );
+
+        adjust.amount = Some(dec("0"));
+        assert_eq!(
+            Transaction::try_from(adjust),
+            Err(RecordError::InvalidAmount(AmountError::Zero))
+        );
+    }
+
+    #[test]
+    fn currency_codes_are_3_letters() {
+        assert_eq!(Currency::new(" eur ").unwrap().to_string(), "EUR");
+        assert!(Currency::new("").unwrap().is_empty());
+        for code in ["E,U", "EURO", "E1R", "€"].iter() {
+            assert_eq!(
+                Currency::new(code),
+                Err(RecordError::InvalidCurrency(code.to_string()))
+            );
+        }
+        assert!(serde_json::from_str::<Currency>(r#""US""#).is_err());
     }
 }
@@ -0,0 +1,161 @@
+//! Module for validating transactions before they reach the transaction engine.
+
+use crate::structs::Transaction;
+use rust_decimal::Decimal;
+use std::fmt;
+
+///Default number of places past the decimal point an amount can have.
+pub const DEFAULT_MAX_SCALE: u32 = 4;
+
+///Reasons for rejecting the amount of a transaction.
+#[derive(Debug, PartialEq, Clone)]
+pub enum AmountError {
+    ///Amount is zero or negative.
+    NotPositive(Decimal),
+    ///Adjustment amount is zero.
+    Zero,
+    ///Amount has more places past the decimal point than allowed.
+    TooPrecise(Decimal, u32),
+    ///Amount is larger than the allowed maximum.
+    TooLarge(Decimal, Decimal),
+}
+
+impl fmt::Display for AmountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AmountError::NotPositive(amount) => write!(f, "amount '{}' is not positive", amount),
+            AmountError::Zero => write!(f, "amount is zero"),
+            AmountError::TooPrecise(amount, max_scale) => write!(
+                f,
+                "amount '{}' has more than {} decimal places",
+                amount, max_scale
+            ),
+            AmountError::TooLarge(amount, max_amount) => {
+                write!(f, "amount '{}' is larger than '{}'", amount, max_amount)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AmountError {}
+
+///Validates amounts of deposits, withdrawals and adjustments.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Validator {
+    ///Maximum places past the decimal point, trailing zeros are not counted.
+    max_scale: u32,
+    ///Maximum amount of a single transaction, no limit when missing.
+    max_amount: Option<Decimal>,
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Self {
+            max_scale: DEFAULT_MAX_SCALE,
+            max_amount: None,
+        }
+    }
+}
+
+impl Validator {
+    ///Create a validator that allows 4 decimal places and has no maximum amount.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///Set the maximum places past the decimal point.
+    pub fn with_max_scale(mut self, max_scale: u32) -> Self {
+        self.max_scale = max_scale;
+        self
+    }
+
+    ///Set the maximum amount of a single transaction.
+    pub fn with_max_amount(mut self, max_amount: Decimal) -> Self {
+        self.max_amount = Some(max_amount);
+        self
+    }
+
+    ///Check the amount of a deposit, withdrawal or adjustment. Other transaction types have no amount to check.
+    pub fn validate(&self, data: &Transaction) -> Result<(), AmountError> {
+        match data {
+            //adjustments are signed so the limits apply to their size
+            Transaction::Adjust { amount, .. } => self.validate_amount(amount.abs()),
+            _ => match data.amount() {
+                Some(amount) => self.validate_amount(amount.value()),
+                None => Ok(()),
+            },
+        }
+    }
+
+    ///Check a single amount.
+    pub fn validate_amount(&self, amount: Decimal) -> Result<(), AmountError> {
+        if amount <= Decimal::ZERO {
+            return Err(AmountError::NotPositive(amount));
+        }
+
+        //"1.50000" is as precise as "1.5" so trailing zeros are ignored
+        if amount.normalize().scale() > self.max_scale {
+            return Err(AmountError::TooPrecise(amount, self.max_scale));
+        }
+
+        if let Some(max_amount) = self.max_amount {
+            if amount > max_amount {
+                return Err(AmountError::TooLarge(amount, max_amount));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn amounts_must_be_positive() {
+        let validator = Validator::new();
+
+        assert_eq!(
+            validator.validate_amount(dec("0")),
+            Err(AmountError::NotPositive(dec("0")))
+        );
+        assert_eq!(
+            validator.validate_amount(dec("-1.5")),
+            Err(AmountError::NotPositive(dec("-1.5")))
+        );
+        assert_eq!(validator.validate_amount(dec("1.5")), Ok(()));
+    }
+
+    #[test]
+    fn trailing_zeros_do_not_count_towards_the_scale() {
+        let validator = Validator::new();
+
+        assert_eq!(validator.validate_amount(dec("1.123400")), Ok(()));
+        assert_eq!(
+            validator.validate_amount(dec("1.12345")),
+            Err(AmountError::TooPrecise(dec("1.12345"), 4))
+        );
+        assert_eq!(
+            Validator::new()
+                .with_max_scale(2)
+                .validate_amount(dec("1.123")),
+            Err(AmountError::TooPrecise(dec("1.123"), 2))
+        );
+    }
+
+    #[test]
+    fn amounts_over_the_maximum_are_too_large() {
+        let validator = Validator::new().with_max_amount(dec("100"));
+
+        assert_eq!(validator.validate_amount(dec("100")), Ok(()));
+        assert_eq!(
+            validator.validate_amount(dec("100.0001")),
+            Err(AmountError::TooLarge(dec("100.0001"), dec("100")))
+        );
+    }
+}
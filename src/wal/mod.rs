@@ -0,0 +1,231 @@
+//! Module for the append-only event log. Every applied transaction is logged with it's balance deltas
+//! so the engine state can be rebuilt by replay after a crash.
+
+use crate::checkpoint::cut_to;
+use crate::checkpoint::OutputLengths;
+use crate::snapshot::SnapshotTransaction;
+use crate::structs::AccountKey;
+use crate::structs::ClientId;
+use crate::structs::Currency;
+use crate::structs::Transaction;
+use crate::structs::TransactionType;
+use crate::Result;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+
+///Number of events written between two syncs to disk when no batch size is set.
+pub const DEFAULT_BATCH_SIZE: usize = 100;
+
+///Change of one account caused by an applied transaction.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct AccountDelta {
+    ///Client id and currency of the account.
+    #[serde(flatten)]
+    pub key: AccountKey,
+    ///Change of available funds.
+    pub available: Decimal,
+    ///Change of held funds.
+    pub held: Decimal,
+    ///Change of total funds.
+    pub total: Decimal,
+    ///Locked state after the transaction.
+    pub locked: bool,
+}
+
+///Processed transaction together with everything it changed, rejected transactions are only logged when they are kept.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Event {
+    ///Input line of the record that produced the event, for JSON arrays this is the element number.
+    #[serde(default)]
+    pub line: u64,
+    ///Hash of the input up to the end of the record, replay only skips records of the input the log was written for.
+    #[serde(default)]
+    pub input_hash: u64,
+    ///Client of the transaction.
+    pub client: ClientId,
+    ///Type of the transaction.
+    #[serde(rename = "type")]
+    pub col_type: TransactionType,
+    ///Changes of every account the transaction touched.
+    pub deltas: Vec<AccountDelta>,
+    ///State of the stored transaction that was added or disputed.
+    pub stored: Option<SnapshotTransaction>,
+    ///Rejected deposit, withdrawal or transfer that is kept so it cannot be disputed.
+    #[serde(default)]
+    pub rejected: Option<Transaction>,
+    ///Fee credited to the house account in the given currency.
+    pub fee: Option<(Currency, Decimal)>,
+    ///Lengths of the outputs once the rows of the record were written, a recovered run cuts them back to these.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outputs: Option<OutputLengths>,
+}
+
+///Append-only log of events written as one JSON object per line.
+///Events are synced to disk in batches, a crash can lose at most the events of the unfinished batch.
+#[derive(Debug)]
+pub struct EventLog {
+    ///Buffered log file.
+    writer: BufWriter<File>,
+    ///Number of events written between two syncs.
+    batch_size: usize,
+    ///Number of events written since the last sync.
+    pending: usize,
+}
+
+impl EventLog {
+    ///Open a log file for appending, it is created when missing.
+    ///A last line without a newline was cut by a crash, it is dropped so new events start on a line of their own.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let length = complete_len(&mut file)?;
+        file.set_len(length)?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+            batch_size: DEFAULT_BATCH_SIZE,
+            pending: 0,
+        })
+    }
+
+    ///Open a log file for appending after cutting it back to the given length, events after it are dropped.
+    ///This is how a run resumed from a checkpoint drops the events it is going to log again.
+    pub fn open_truncated<P: AsRef<Path>>(path: P, length: u64) -> Result<Self> {
+        let event_log = Self::open(&path)?;
+        cut_to(event_log.writer.get_ref(), path.as_ref(), length)?;
+        Ok(event_log)
+    }
+
+    ///Check if the next appended event fills the batch, the log is synced right after it.
+    pub fn syncs_next(&self) -> bool {
+        self.pending + 1 >= self.batch_size
+    }
+
+    ///Set the number of events written between two syncs, zero is treated as one.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    ///Append one event, the log is synced when the batch is full.
+    pub fn append(&mut self, event: &Event) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, event)?;
+        writeln!(self.writer)?;
+        self.pending += 1;
+        if self.pending >= self.batch_size {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    ///Length of the log file, call `sync` first so buffered events are counted.
+    pub fn synced_len(&self) -> Result<u64> {
+        Ok(self.writer.get_ref().metadata()?.len())
+    }
+
+    ///Write buffered events and wait until they are on disk.
+    pub fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.pending = 0;
+        Ok(())
+    }
+}
+
+///Length of a file up to and including it's last newline, the file is read backwards from the end.
+fn complete_len(file: &mut File) -> Result<u64> {
+    let mut end = file.metadata()?.len();
+    let mut buffer = [0; 4096];
+    while end > 0 {
+        let start = end.saturating_sub(buffer.len() as u64);
+        let chunk = &mut buffer[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        if let Some(index) = chunk.iter().rposition(|byte| *byte == b'\n') {
+            return Ok(start + index as u64 + 1);
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    ///Path of a temporary file unique to one test.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("csv_parser_{}_{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn event(line: u64) -> Event {
+        Event {
+            line,
+            input_hash: 0,
+            client: ClientId(1),
+            col_type: TransactionType::Freeze,
+            deltas: Vec::new(),
+            stored: None,
+            rejected: None,
+            fee: None,
+            outputs: None,
+        }
+    }
+
+    ///Events of every complete line of a log.
+    fn logged(path: &Path) -> Vec<Event> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn line_cut_by_a_crash_is_dropped_on_open() {
+        let path = temp_path("wal_cut.log");
+        let mut event_log = EventLog::open(&path).unwrap();
+        event_log.append(&event(2)).unwrap();
+        event_log.sync().unwrap();
+        drop(event_log);
+        let complete = fs::read_to_string(&path).unwrap();
+        fs::write(&path, format!("{}{{\"line\":3,\"cli", complete)).unwrap();
+
+        let mut event_log = EventLog::open(&path).unwrap();
+        assert_eq!(event_log.synced_len().unwrap(), complete.len() as u64);
+        event_log.append(&event(4)).unwrap();
+        event_log.sync().unwrap();
+
+        assert_eq!(logged(&path), vec![event(2), event(4)]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn long_line_without_any_newline_is_dropped() {
+        let path = temp_path("wal_no_newline.log");
+        fs::write(&path, "x".repeat(10_000)).unwrap();
+
+        let mut event_log = EventLog::open(&path).unwrap();
+        assert_eq!(event_log.synced_len().unwrap(), 0);
+        event_log.append(&event(2)).unwrap();
+        event_log.sync().unwrap();
+
+        assert_eq!(logged(&path), vec![event(2)]);
+        fs::remove_file(&path).unwrap();
+    }
+}